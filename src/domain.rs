@@ -1,17 +1,271 @@
-use crate::{Fallible, MalipoError};
-use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Sum;
+use std::num::NonZeroU32;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+
+use crate::{Fallible, MalipoError, ParseAmountError, ParseIdempotencyKeyError};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 /// Client ID
 pub type ClientId = u16;
 /// Transaction ID
 pub type TransactionId = u32;
-/// Monetary Amount
-pub type Amount = f64;
 
-#[derive(Clone, Copy, Debug, Deserialize)]
+/// External idempotency key, such as a payment processor reference. Keys
+/// are non-empty text of up to 64 bytes. Each distinct key is kept once in
+/// a table shared by the whole process, and a key is only its index into
+/// that table, so transactions stay small and `Copy`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey(NonZeroU32);
+
+/// Text of the idempotency keys seen so far, in the order first seen. Keys
+/// are never removed, so their text lives as long as the process.
+#[derive(Default)]
+struct KeyTable {
+    keys: Vec<&'static str>,
+    indexes: HashMap<&'static str, NonZeroU32>,
+}
+
+/// The process's key table
+fn key_table() -> MutexGuard<'static, KeyTable> {
+    static TABLE: OnceLock<Mutex<KeyTable>> = OnceLock::new();
+    TABLE
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+impl IdempotencyKey {
+    /// Longest key, in bytes
+    pub const MAX_LEN: usize = 64;
+
+    /// Key as text
+    pub fn as_str(&self) -> &'static str {
+        key_table().keys[self.0.get() as usize - 1]
+    }
+
+    /// Position of the key in the key table, from 1
+    pub(crate) fn index(&self) -> u32 {
+        self.0.get()
+    }
+
+    /// Key at `index` in the key table, `None` if there is none
+    pub(crate) fn from_index(index: u32) -> Option<Self> {
+        let index = NonZeroU32::new(index)?;
+        let known = index.get() as usize <= key_table().keys.len();
+        known.then_some(Self(index))
+    }
+}
+
+impl FromStr for IdempotencyKey {
+    type Err = ParseIdempotencyKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > Self::MAX_LEN {
+            return Err(ParseIdempotencyKeyError(s.to_owned()));
+        }
+        let mut table = key_table();
+        if let Some(&index) = table.indexes.get(s) {
+            return Ok(Self(index));
+        }
+        let index = u32::try_from(table.keys.len() + 1)
+            .ok()
+            .and_then(NonZeroU32::new)
+            .ok_or_else(|| ParseIdempotencyKeyError(s.to_owned()))?;
+        let key: &'static str = Box::leak(s.into());
+        table.keys.push(key);
+        table.indexes.insert(key, index);
+        Ok(Self(index))
+    }
+}
+
+impl fmt::Display for IdempotencyKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for IdempotencyKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("IdempotencyKey")
+            .field(&self.as_str())
+            .finish()
+    }
+}
+
+impl Serialize for IdempotencyKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for IdempotencyKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = <&str>::deserialize(deserializer)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Monetary Amount, held exactly as a whole number of ten-thousandths
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+impl Amount {
+    /// Number of decimal places kept
+    pub const DECIMALS: u32 = 4;
+    /// Ten-thousandths in one unit
+    pub const SCALE: i64 = 10_i64.pow(Self::DECIMALS);
+    /// No funds
+    pub const ZERO: Amount = Amount(0);
+
+    /// Amount from a count of ten-thousandths
+    pub const fn from_raw(raw: i64) -> Self {
+        Self(raw)
+    }
+
+    /// Count of ten-thousandths
+    pub const fn raw(self) -> i64 {
+        self.0
+    }
+
+    /// Absolute value
+    pub fn abs(self) -> Self {
+        Self(self.0.abs())
+    }
+
+    /// Sum of two amounts, `None` if it is out of range
+    pub fn checked_add(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_add(rhs.0).map(Self)
+    }
+
+    /// Difference of two amounts, `None` if it is out of range
+    pub fn checked_sub(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_sub(rhs.0).map(Self)
+    }
+}
+
+/// Sum of two amounts, failing with `AmountOverflow` if it is out of range
+fn add_checked(lhs: Amount, rhs: Amount) -> Fallible<Amount> {
+    lhs.checked_add(rhs).ok_or(MalipoError::AmountOverflow)
+}
+
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    /// Parse a decimal string such as `-12.5` exactly. Digits beyond the
+    /// fourth decimal place must be zero.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseAmountError(s.to_owned());
+        let (negative, digits) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (whole, frac) = digits.split_once('.').unwrap_or((digits, ""));
+        let all_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if whole.is_empty() && frac.is_empty() || !all_digits(whole) || !all_digits(frac) {
+            return Err(err());
+        }
+        let (frac, rest) = frac.split_at(frac.len().min(Self::DECIMALS as usize));
+        if rest.bytes().any(|b| b != b'0') {
+            return Err(err());
+        }
+        let whole: i64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| err())?
+        };
+        let frac: i64 = format!("{:0<width$}", frac, width = Self::DECIMALS as usize)
+            .parse()
+            .map_err(|_| err())?;
+        let raw = whole
+            .checked_mul(Self::SCALE)
+            .and_then(|raw| raw.checked_add(frac))
+            .ok_or_else(err)?;
+        Ok(Self(if negative { -raw } else { raw }))
+    }
+}
+
+impl fmt::Display for Amount {
+    /// Formats with exactly four decimal places, e.g. `-0.5000`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let raw = self.0.unsigned_abs();
+        let scale = Self::SCALE as u64;
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            raw / scale,
+            raw % scale,
+            width = Self::DECIMALS as usize
+        )
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+    fn add(self, rhs: Amount) -> Amount {
+        Amount(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, rhs: Amount) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+    fn sub(self, rhs: Amount) -> Amount {
+        Amount(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, rhs: Amount) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Amount>>(iter: I) -> Amount {
+        iter.fold(Amount::ZERO, Add::add)
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ser_float(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = <&str>::deserialize(deserializer)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 /// Transaction Type
 pub enum TransactionType {
+    /// Authorization hold
+    Authorize,
+    /// Capture of an authorization
+    Capture,
     /// Charge back
     Chargeback,
     /// Deposit
@@ -20,11 +274,24 @@ pub enum TransactionType {
     Dispute,
     /// Resolve
     Resolve,
+    /// Release of an authorization
+    Void,
     /// Withdrawal
     Withdrawal,
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize)]
+impl TransactionType {
+    /// Whether rows of this type carry their own amount, rather than
+    /// referencing an earlier transaction's
+    pub fn carries_amount(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Authorize | TransactionType::Deposit | TransactionType::Withdrawal
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
 /// Client's Account
 pub struct Account {
     #[serde(rename = "client")]
@@ -48,16 +315,59 @@ impl Account {
         }
     }
 
+    /// Rebuild an account from stored balances
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn from_parts(
+        client_id: ClientId,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+        locked: bool,
+    ) -> Self {
+        Self {
+            client_id,
+            available,
+            held,
+            total,
+            locked,
+        }
+    }
+
+    /// Hold funds for an authorization
+    pub fn authorize(&mut self, amount: Amount) -> Fallible<()> {
+        if amount > self.available {
+            return Err(MalipoError::InsufficientAccountFunds);
+        }
+        self.available -= amount;
+        self.held += amount;
+        Ok(())
+    }
+
+    /// Debit previously authorized funds
+    pub fn capture(&mut self, amount: Amount) {
+        self.held -= amount;
+        self.total -= amount;
+    }
+
+    /// Release previously authorized funds
+    pub fn void(&mut self, amount: Amount) {
+        self.held -= amount;
+        self.available += amount;
+    }
+
     /// Perform a chargeback on this account
     pub fn chargeback(&mut self, amount: Amount) {
         self.held -= amount;
         self.total -= amount;
         self.locked = true;
     }
-    /// Deposit into this account
-    pub fn deposit(&mut self, amount: Amount) {
-        self.available += amount;
-        self.total += amount;
+    /// Deposit into this account. Nothing changes if a balance would
+    /// overflow.
+    pub fn deposit(&mut self, amount: Amount) -> Fallible<()> {
+        let available = add_checked(self.available, amount)?;
+        self.total = add_checked(self.total, amount)?;
+        self.available = available;
+        Ok(())
     }
 
     /// Perform a dispute of the amount on this account
@@ -72,6 +382,34 @@ impl Account {
         self.available += amount;
     }
 
+    /// Dispute a withdrawal, holding the withdrawn amount pending the outcome
+    pub fn dispute_withdrawal(&mut self, amount: Amount) {
+        self.held += amount;
+        self.total += amount;
+    }
+
+    /// Resolve a withdrawal dispute, letting the withdrawal stand
+    pub fn resolve_withdrawal(&mut self, amount: Amount) {
+        self.held -= amount;
+        self.total -= amount;
+    }
+
+    /// Charge back a disputed withdrawal, returning the funds to the client
+    pub fn chargeback_withdrawal(&mut self, amount: Amount) {
+        self.held -= amount;
+        self.available += amount;
+        self.locked = true;
+    }
+
+    /// Fold another account's balances into this one, keeping it locked
+    /// if either account was locked. Nothing changes if a balance would
+    /// overflow.
+    pub fn merge(&mut self, other: &Account) -> Fallible<()> {
+        self.adjust(other.available, other.held, other.total)?;
+        self.locked |= other.locked;
+        Ok(())
+    }
+
     /// Withdraw funds from account
     pub fn withdraw(&mut self, amount: Amount) -> Fallible<()> {
         if amount > self.available {
@@ -82,20 +420,101 @@ impl Account {
         Ok(())
     }
 
+    /// Funds available for withdrawal
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    /// Funds held for disputes
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    /// Total funds, available and held
+    pub fn total(&self) -> Amount {
+        self.total
+    }
+
     /// Check if account is frozen/locked
     pub fn is_frozen(&self) -> bool {
         self.locked
     }
 
+    /// Shift the balances by the given amounts, bypassing the funds checks.
+    /// Nothing changes if a balance would overflow.
+    pub(crate) fn adjust(
+        &mut self,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+    ) -> Fallible<()> {
+        let available = add_checked(self.available, available)?;
+        let held = add_checked(self.held, held)?;
+        self.total = add_checked(self.total, total)?;
+        self.available = available;
+        self.held = held;
+        Ok(())
+    }
+
+    /// Lock or unlock the account
+    pub(crate) fn set_locked(&mut self, locked: bool) {
+        self.locked = locked;
+    }
+
     /// check that account invariants are not violated
     #[cfg(debug_assertions)]
     pub fn check_invariants(&self) {
         assert!(self.total >= self.available);
-        assert!((self.total - (self.available + self.held)).abs() < f64::EPSILON);
+        assert_eq!(self.total, self.available + self.held);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+/// Client's available and held funds, for minimal reports
+pub struct MinimalAccount {
+    #[serde(rename = "client")]
+    /// Client ID
+    pub client_id: ClientId,
+    #[serde(serialize_with = "ser_float")]
+    /// Funds available for withdrawal
+    pub available: Amount,
+    #[serde(serialize_with = "ser_float")]
+    /// Funds held for disputes
+    pub held: Amount,
+}
+impl From<Account> for MinimalAccount {
+    fn from(acc: Account) -> Self {
+        Self {
+            client_id: acc.client_id,
+            available: acc.available,
+            held: acc.held,
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Transaction lifecycle state
+pub enum TxnState {
+    /// Applied and not under dispute
+    #[default]
+    Normal,
+    /// Under dispute, funds held
+    Disputed,
+    /// Dispute resolved, funds released
+    Resolved,
+    /// Dispute ended in a chargeback
+    ChargedBack,
+    /// Authorization holding funds
+    Authorized,
+    /// Authorization captured
+    Captured,
+    /// Authorization voided
+    Voided,
+    /// Reversed by an operator
+    Reversed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
 /// Transaction
 pub struct Transaction {
     #[serde(rename = "type")]
@@ -110,22 +529,312 @@ pub struct Transaction {
     #[serde(default)]
     /// Amount
     pub amount: Option<Amount>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// External key identifying the same payment across transaction IDs
+    pub idempotency_key: Option<IdempotencyKey>,
     #[serde(skip)]
-    disputed: bool,
+    state: TxnState,
 }
 impl Transaction {
-    /// Mark a transaction as disputed
-    pub fn mark_as_disputed(&mut self) {
-        self.disputed = true;
+    /// Create a new transaction
+    pub fn new(
+        type_: TransactionType,
+        client_id: ClientId,
+        id: TransactionId,
+        amount: Option<Amount>,
+    ) -> Self {
+        Self {
+            type_,
+            client_id,
+            id,
+            amount,
+            idempotency_key: None,
+            state: TxnState::Normal,
+        }
+    }
+    /// Current lifecycle state
+    pub fn state(&self) -> TxnState {
+        self.state
+    }
+    /// Check the rules for the transaction's type: deposits, withdrawals and
+    /// authorizations need a positive amount, while any amount on the rows
+    /// referencing an earlier transaction is ignored
+    pub fn validate(&self) -> Fallible<()> {
+        let invalid = |reason: &str| {
+            Err(MalipoError::InvalidTransaction {
+                tx: self.id,
+                reason: format!("{:?} {}", self.type_, reason),
+            })
+        };
+        match (self.type_.carries_amount(), self.amount) {
+            (true, None) => invalid("needs an amount"),
+            (true, Some(amount)) if amount <= Amount::ZERO => invalid("needs a positive amount"),
+            _ => Ok(()),
+        }
+    }
+    /// Like `validate`, but lets a deposit of zero through, as sent to
+    /// activate an account without funding it
+    pub(crate) fn validate_activation(&self) -> Fallible<()> {
+        match (self.type_, self.amount) {
+            (TransactionType::Deposit, Some(amount)) if amount == Amount::ZERO => Ok(()),
+            _ => self.validate(),
+        }
+    }
+    /// Rebuild a transaction in a stored lifecycle state
+    pub(crate) fn with_state(self, state: TxnState) -> Self {
+        Self { state, ..self }
     }
     /// Check if a transaction is in dispute
     pub fn is_disputed(&self) -> bool {
-        self.disputed
+        self.state == TxnState::Disputed
+    }
+    /// Check if a transaction holds authorized funds
+    pub fn is_authorized(&self) -> bool {
+        self.state == TxnState::Authorized
+    }
+    /// Open a dispute, from a normal or previously resolved transaction
+    pub fn dispute(&mut self) -> Fallible<()> {
+        self.transition(&[TxnState::Normal, TxnState::Resolved], TxnState::Disputed)
     }
     /// Resolve a dispute
-    pub fn resolve_dispute(&mut self) {
-        self.disputed = false;
+    pub fn resolve(&mut self) -> Fallible<()> {
+        self.transition(&[TxnState::Disputed], TxnState::Resolved)
+    }
+    /// Charge back a disputed transaction
+    pub fn chargeback(&mut self) -> Fallible<()> {
+        self.transition(&[TxnState::Disputed], TxnState::ChargedBack)
+    }
+    /// Hold the transaction's funds as an authorization
+    pub fn authorize(&mut self) -> Fallible<()> {
+        self.transition(&[TxnState::Normal], TxnState::Authorized)
+    }
+    /// Capture an authorization
+    pub fn capture(&mut self) -> Fallible<()> {
+        self.transition(&[TxnState::Authorized], TxnState::Captured)
+    }
+    /// Void an authorization
+    pub fn void(&mut self) -> Fallible<()> {
+        self.transition(&[TxnState::Authorized], TxnState::Voided)
+    }
+    /// Reverse a deposit or withdrawal that is not under dispute
+    pub fn reverse(&mut self) -> Fallible<()> {
+        self.transition(&[TxnState::Normal, TxnState::Resolved], TxnState::Reversed)
+    }
+
+    fn transition(&mut self, from: &[TxnState], to: TxnState) -> Fallible<()> {
+        if !from.contains(&self.state) {
+            return Err(MalipoError::InvalidStateTransition {
+                tx: self.id,
+                from: self.state,
+                to,
+            });
+        }
+        self.state = to;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+/// Dispute audit trail entry
+pub struct DisputeAuditEntry {
+    #[serde(rename = "tx")]
+    /// Disputed transaction ID
+    pub id: TransactionId,
+    #[serde(rename = "client")]
+    /// Client ID
+    pub client_id: ClientId,
+    /// Dispute, resolve or chargeback
+    pub action: TransactionType,
+    #[serde(serialize_with = "ser_float")]
+    /// Amount disputed
+    pub amount: Amount,
+    #[serde(serialize_with = "ser_float")]
+    /// Account held funds after the action
+    pub held_after: Amount,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A transaction the engine declined to apply
+pub struct RejectedTransaction {
+    /// Transaction type
+    pub type_: TransactionType,
+    /// Client ID
+    pub client_id: ClientId,
+    /// Transaction ID
+    pub id: TransactionId,
+    /// Why it was rejected
+    pub reason: RejectReason,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Result of executing a transaction
+pub enum TransactionOutcome {
+    /// The transaction was applied to the account
+    Applied,
+    /// The transaction was not applied
+    Rejected {
+        /// Why the transaction was not applied
+        reason: RejectReason,
+    },
+}
+impl TransactionOutcome {
+    /// Rejected outcome with the given reason
+    pub fn rejected(reason: RejectReason) -> Self {
+        Self::Rejected { reason }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+/// Why a transaction was not applied
+pub enum RejectReason {
+    /// The account is frozen
+    AccountFrozen,
+    /// Not enough available funds
+    InsufficientFunds,
+    /// The referenced transaction does not exist
+    UnknownTransaction,
+    /// The referenced transaction is not under dispute
+    NotDisputed,
+    /// The referenced transaction holds no authorized funds
+    NotAuthorized,
+    /// The referenced transaction cannot be disputed in its current state
+    NotDisputable,
+    /// The referenced transaction belongs to another client
+    ClientMismatch,
+    /// The referenced transaction's dispute was already resolved
+    AlreadyResolved,
+    /// The referenced transaction's dispute was resolved, so it cannot be
+    /// charged back
+    ChargebackAfterResolve,
+    /// The amount is missing, zero or negative
+    InvalidAmount,
+    /// The referenced transaction cannot be reversed in its current state
+    NotReversible,
+    /// A transaction with this ID was already applied
+    DuplicateTransaction,
+    /// A transaction with this idempotency key was already applied
+    DuplicateIdempotencyKey,
+    /// The client is new and the engine holds its maximum number of accounts
+    AccountLimitReached,
+    /// Left out of the sample the engine is configured to process
+    NotSampled,
+    /// Only deposits and withdrawals are accepted in append-only mode
+    AppendOnly,
+    /// Applying the transaction would take a balance or total out of range
+    AmountOverflow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+/// What the engine decided for a transaction
+pub enum AuditEvent {
+    /// The transaction was applied
+    Applied {
+        /// The transaction
+        txn: Transaction,
+        /// Client account before the transaction
+        before: Account,
+        /// Client account after the transaction
+        after: Account,
+    },
+    /// The transaction was reversed by `reverse_transaction`
+    Reversed {
+        /// The transaction, as stored before the reversal
+        txn: Transaction,
+        /// Client account before the reversal
+        before: Account,
+        /// Client account after the reversal
+        after: Account,
+    },
+    /// Skipped, the account is frozen
+    SkippedFrozen {
+        /// The transaction
+        txn: Transaction,
+    },
+    /// Skipped, not enough available funds
+    SkippedInsufficientFunds {
+        /// The transaction
+        txn: Transaction,
+    },
+    /// Skipped, the referenced transaction does not exist
+    SkippedUnknownTxn {
+        /// The transaction
+        txn: Transaction,
+    },
+    /// Skipped, the referenced transaction belongs to another client
+    SkippedClientMismatch {
+        /// The transaction
+        txn: Transaction,
+    },
+    /// Skipped, a transaction with this ID was already applied
+    SkippedDuplicate {
+        /// The transaction
+        txn: Transaction,
+    },
+    /// Skipped for any other reason
+    Skipped {
+        /// The transaction
+        txn: Transaction,
+        /// Why it was skipped
+        reason: RejectReason,
+    },
+}
+
+impl AuditEvent {
+    /// Event for a transaction rejected for `reason`
+    pub fn skipped(txn: Transaction, reason: RejectReason) -> Self {
+        match reason {
+            RejectReason::AccountFrozen => AuditEvent::SkippedFrozen { txn },
+            RejectReason::InsufficientFunds => AuditEvent::SkippedInsufficientFunds { txn },
+            RejectReason::UnknownTransaction => AuditEvent::SkippedUnknownTxn { txn },
+            RejectReason::ClientMismatch => AuditEvent::SkippedClientMismatch { txn },
+            RejectReason::DuplicateTransaction => AuditEvent::SkippedDuplicate { txn },
+            reason => AuditEvent::Skipped { txn, reason },
+        }
+    }
+
+    /// Why the transaction was skipped, `None` if it was applied or
+    /// reversed
+    pub fn reason(&self) -> Option<RejectReason> {
+        match *self {
+            AuditEvent::Applied { .. } | AuditEvent::Reversed { .. } => None,
+            AuditEvent::SkippedFrozen { .. } => Some(RejectReason::AccountFrozen),
+            AuditEvent::SkippedInsufficientFunds { .. } => Some(RejectReason::InsufficientFunds),
+            AuditEvent::SkippedUnknownTxn { .. } => Some(RejectReason::UnknownTransaction),
+            AuditEvent::SkippedClientMismatch { .. } => Some(RejectReason::ClientMismatch),
+            AuditEvent::SkippedDuplicate { .. } => Some(RejectReason::DuplicateTransaction),
+            AuditEvent::Skipped { reason, .. } => Some(reason),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Why a dispute, resolve or chargeback cannot apply to the transaction it references
+pub enum InvalidReference {
+    /// No deposit or withdrawal with this ID exists
+    Missing,
+    /// The referenced transaction only appears later in the input
+    Later,
+    /// The referenced transaction belongs to another client
+    WrongClient,
+}
+
+/// Item identified by an ID of type `Id`
+pub trait Keyed<Id> {
+    /// The item's ID
+    fn key(&self) -> Id;
+}
+
+impl Keyed<ClientId> for Account {
+    fn key(&self) -> ClientId {
+        self.client_id
+    }
+}
+
+impl Keyed<TransactionId> for Transaction {
+    fn key(&self) -> TransactionId {
+        self.id
     }
 }
 
@@ -137,14 +846,49 @@ pub trait Store<Id, Item> {
     fn delete(&mut self, id: Id) -> Fallible<()>;
     /// Get an item using its ID
     fn get(&mut self, id: Id) -> Fallible<Item>;
+    /// Look up an item using its ID, without creating it or changing the
+    /// store. The default scans every item; stores with an index of their
+    /// IDs can do better.
+    fn find(&self, id: Id) -> Fallible<Option<Item>>
+    where
+        Item: Keyed<Id>,
+        Id: PartialEq,
+    {
+        for item in self.iter()? {
+            let item = item?;
+            if item.key() == id {
+                return Ok(Some(item));
+            }
+        }
+        Ok(None)
+    }
+    /// Look up several items at once, in the order of `ids`, with `None` for
+    /// each ID not in the store
+    fn get_many(&self, ids: &[Id]) -> Fallible<Vec<Option<Item>>>
+    where
+        Item: Keyed<Id>,
+        Id: Copy + PartialEq,
+    {
+        ids.iter().map(|id| self.find(*id)).collect()
+    }
     /// Update an item
     fn update(&mut self, item: Item) -> Fallible<()>;
-    /// An iterator over all items in the store
-    fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Item> + '_>>;
+    /// An iterator over all items in the store. Stores that read items
+    /// lazily yield an error for an item that could not be read.
+    fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Item>> + '_>>;
+    /// An iterator over the IDs of all items in the store. The default reads
+    /// every item; stores with an index of their IDs can do better.
+    fn ids(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Id>> + '_>>
+    where
+        Item: Keyed<Id> + 'static,
+    {
+        Ok(Box::new(
+            self.iter()?.map(|item| item.map(|item| item.key())),
+        ))
+    }
 }
 
-/// Serialize floats
-pub fn ser_float<S: Serializer>(float: &f64, serializer: S) -> Result<S::Ok, S::Error> {
-    let float_as_str = format!("{:.4}", float);
-    serializer.serialize_str(&float_as_str)
+/// Serialize amounts with four decimal places
+pub fn ser_float<S: Serializer>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&amount.to_string())
 }
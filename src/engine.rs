@@ -1,10 +1,174 @@
+use std::collections::{HashMap, HashSet};
+
 use crate::domain::*;
-use crate::{Fallible, MalipoError};
+use crate::{
+    AccountWriter, AccountsMemStore, CsvDataReader, CsvWriterStdout, Fallible, MalipoError,
+    TransactionsMemStore,
+};
+
+/// Record a skipped transaction as a tracing event
+macro_rules! trace_skip {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+/// Running totals of the funds moved by the engine
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    /// Sum of applied deposits
+    pub deposited: Amount,
+    /// Sum of applied withdrawals, less those under dispute or charged back
+    pub withdrawn: Amount,
+    /// Sum of applied chargebacks
+    pub charged_back: Amount,
+}
+
+/// Outcome of processing a whole CSV input
+#[derive(Debug)]
+pub struct ProcessReport {
+    /// Funds moved by the engine
+    pub stats: Stats,
+    /// Rows skipped for an invalid amount or idempotency key, in input order
+    pub skipped: Vec<MalipoError>,
+    /// Number of withdrawals the engine rejected
+    pub withdrawals_rejected: usize,
+}
+
+/// Callback receiving processed transactions
+pub type TransactionSink = Box<dyn FnMut(&Transaction)>;
+
+/// Callback running periodic maintenance on the engine
+pub type MaintenanceHook = Box<dyn FnMut(&mut PaymentsEngine)>;
+
+/// Receives an event for every decision the engine makes
+pub trait AuditLog {
+    /// Record an event
+    fn on_event(&mut self, event: &AuditEvent) -> Fallible<()>;
+}
+
+/// How the engine handles a transaction rejected for a given reason
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Undo the transaction and fail with `TransactionRejected`
+    Abort,
+    /// Reject the transaction and carry on
+    #[default]
+    Skip,
+    /// Like `Skip`, also logging a warning when the `tracing` feature is on
+    Warn,
+}
+
+/// Engine configuration
+pub struct EngineConfig {
+    /// Receives every transaction that was applied, in order
+    pub applied_sink: Option<TransactionSink>,
+    /// Fail a resolve that would release more than the account holds,
+    /// instead of clamping the release to the held funds
+    pub error_on_over_resolve: bool,
+    /// Fail `finish` when funds were not conserved
+    pub verify_conservation: bool,
+    /// Fail `execute_batch` when a dispute, resolve or chargeback precedes
+    /// the transaction it references
+    pub strict_ordering: bool,
+    /// Reject a resolve of an already resolved dispute as `AlreadyResolved`
+    /// rather than `NotDisputed`
+    pub strict_resolve: bool,
+    /// Reject a chargeback of a resolved dispute as `ChargebackAfterResolve`
+    /// rather than `NotDisputed`
+    pub strict_chargeback: bool,
+    /// Receives an event for every transaction applied or skipped
+    pub audit_log: Option<Box<dyn AuditLog>>,
+    /// Reject transactions for new clients once this many accounts exist
+    pub max_accounts: Option<usize>,
+    /// Fail a dispute that leaves the account holding more than its total
+    /// funds, as when the disputed deposit was already withdrawn
+    pub bound_dispute_holds: bool,
+    /// Process only about this fraction of the transactions, from 0 to 1.
+    /// Whether a transaction is kept depends only on its ID and `seed`, so
+    /// disputes, resolves and chargebacks are kept exactly when the
+    /// transaction they reference is.
+    pub sample_rate: Option<f64>,
+    /// Seed choosing which transactions `sample_rate` keeps
+    pub seed: u64,
+    /// Run `maintenance_hook` after every this many applied transactions
+    pub maintenance_interval: Option<usize>,
+    /// Periodic maintenance, such as a snapshot or a metrics flush
+    pub maintenance_hook: Option<MaintenanceHook>,
+    /// Let disputes target withdrawals as well as deposits; when off, such
+    /// disputes are rejected as `NotDisputable`. On by default.
+    pub allow_withdrawal_disputes: bool,
+    /// Accept only deposits and withdrawals, rejecting every other type as
+    /// `AppendOnly` and refusing `reverse_transaction`, `merge_clients` and
+    /// `unwind`, so nothing recorded is ever undone
+    pub append_only: bool,
+    /// Keep at most this many rejected transactions for `rejected`, 10 000
+    /// by default; later ones are only counted in `rejection_counts`. `None`
+    /// keeps them all.
+    pub max_rejected: Option<usize>,
+    /// Handling of rejected transactions by reason, such as aborting on
+    /// `AmountOverflow` while skipping `DuplicateTransaction`. Reasons
+    /// missing from the map are skipped.
+    pub error_policy: HashMap<RejectReason, ErrorPolicy>,
+    /// Accept a deposit of zero to create the client's account without
+    /// funding it. The deposit is stored, so its ID cannot be reused. Zero
+    /// withdrawals and authorizations are still rejected as `InvalidAmount`.
+    pub allow_zero_deposit_activation: bool,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            applied_sink: None,
+            error_on_over_resolve: false,
+            verify_conservation: false,
+            strict_ordering: false,
+            strict_resolve: false,
+            strict_chargeback: false,
+            audit_log: None,
+            max_accounts: None,
+            bound_dispute_holds: false,
+            sample_rate: None,
+            seed: 0,
+            maintenance_interval: None,
+            maintenance_hook: None,
+            allow_withdrawal_disputes: true,
+            append_only: false,
+            max_rejected: Some(10_000),
+            error_policy: HashMap::new(),
+            allow_zero_deposit_activation: false,
+        }
+    }
+}
+
+/// How `execute` handles a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Checked and applied as read
+    Normal,
+    /// Let through a frozen account
+    Supervised,
+    /// Reversal of the stored transaction, by `reverse_transaction`
+    Reversal,
+}
 
 /// Payments Engine
 pub struct PaymentsEngine {
     accounts: Box<dyn Store<ClientId, Account>>,
     transactions: Box<dyn Store<TransactionId, Transaction>>,
+    stats: Stats,
+    dispute_audit: Vec<DisputeAuditEntry>,
+    rejected: Vec<RejectedTransaction>,
+    rejection_counts: HashMap<(TransactionType, RejectReason), usize>,
+    idempotency_keys: Option<HashSet<IdempotencyKey>>,
+    processed: usize,
+    applied: usize,
+    last_activity: HashMap<ClientId, usize>,
+    noops: Vec<TransactionId>,
+    positions: HashMap<ClientId, (Amount, Amount)>,
+    account_count: Option<usize>,
+    config: EngineConfig,
 }
 
 impl PaymentsEngine {
@@ -12,71 +176,1019 @@ impl PaymentsEngine {
     pub fn new(
         accounts: Box<dyn Store<ClientId, Account>>,
         transactions: Box<dyn Store<TransactionId, Transaction>>,
+    ) -> Self {
+        Self::with_config(accounts, transactions, EngineConfig::default())
+    }
+
+    /// Creates an engine with the given configuration.
+    pub fn with_config(
+        accounts: Box<dyn Store<ClientId, Account>>,
+        transactions: Box<dyn Store<TransactionId, Transaction>>,
+        config: EngineConfig,
     ) -> Self {
         Self {
             accounts,
             transactions,
+            stats: Stats::default(),
+            dispute_audit: Vec::new(),
+            rejected: Vec::new(),
+            rejection_counts: HashMap::new(),
+            idempotency_keys: None,
+            processed: 0,
+            applied: 0,
+            last_activity: HashMap::new(),
+            noops: Vec::new(),
+            positions: HashMap::new(),
+            account_count: None,
+            config,
+        }
+    }
+    /// Process a CSV file of transactions with in-memory stores and write
+    /// the resulting accounts with `writer` to `output`, or stdout when `None`.
+    pub fn process_file(
+        input: &str,
+        output: Option<&str>,
+        writer: &dyn AccountWriter,
+    ) -> Fallible<ProcessReport> {
+        Self::process_reader(CsvDataReader::new(input)?, output, writer)
+    }
+
+    /// Like [`PaymentsEngine::process_file`], reading from any CSV source.
+    /// Rows with an invalid amount or idempotency key are skipped and listed
+    /// in the report.
+    pub fn process_reader<R: std::io::Read>(
+        input: CsvDataReader<R>,
+        output: Option<&str>,
+        writer: &dyn AccountWriter,
+    ) -> Fallible<ProcessReport> {
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::new(acc_store, txn_store);
+        let mut skipped = vec![];
+        for txn in input {
+            match txn {
+                Ok(txn) => engine.execute_transaction(txn)?,
+                Err(
+                    e @ (MalipoError::InvalidAmount { .. }
+                    | MalipoError::InvalidIdempotencyKey { .. }),
+                ) => {
+                    skipped.push(e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
         }
+        engine.finish()?;
+        let withdrawals_rejected: usize = engine
+            .rejection_counts()
+            .iter()
+            .filter(|((type_, _), _)| *type_ == TransactionType::Withdrawal)
+            .map(|(_, count)| count)
+            .sum();
+        match output {
+            Some(path) => writer.write(engine.accounts()?, &mut std::fs::File::create(path)?)?,
+            None => writer.write(engine.accounts()?, &mut std::io::stdout())?,
+        }
+        Ok(ProcessReport {
+            stats: engine.stats(),
+            skipped,
+            withdrawals_rejected,
+        })
     }
-    /// Execute a transaction
-    pub fn execute_transaction(&mut self, txn: Transaction) -> Fallible<()> {
+
+    /// Execute transactions on `num_threads` worker threads with in-memory
+    /// stores and return the resulting accounts, along with the rows skipped
+    /// for an invalid amount or idempotency key.
+    ///
+    /// Each client's transactions are routed to a single worker in arrival
+    /// order, so they apply exactly as they would sequentially. A dispute
+    /// referencing another client's transaction is rejected either way,
+    /// though as an unknown transaction when the clients land on different
+    /// workers. Transaction IDs are claimed as rows are routed, and a
+    /// deposit, withdrawal or authorization reusing an ID claimed for
+    /// another worker is dropped, as the sequential duplicate check would;
+    /// unlike that check, an ID stays claimed even if its first row is then
+    /// rejected.
+    pub fn execute_parallel<I: IntoIterator<Item = Fallible<Transaction>>>(
+        txns: I,
+        num_threads: usize,
+    ) -> Fallible<(AccountsMemStore, Vec<MalipoError>)> {
+        let num_threads = num_threads.max(1);
+        std::thread::scope(|scope| {
+            let (senders, workers): (Vec<_>, Vec<_>) = (0..num_threads)
+                .map(|_| {
+                    let (sender, receiver) = std::sync::mpsc::sync_channel::<Transaction>(1024);
+                    let worker = scope.spawn(move || -> Fallible<Vec<Account>> {
+                        let acc_store = Box::new(AccountsMemStore::new());
+                        let txn_store = Box::new(TransactionsMemStore::new());
+                        let mut engine = PaymentsEngine::new(acc_store, txn_store);
+                        for txn in receiver {
+                            engine.execute_transaction(txn)?;
+                        }
+                        let accounts = engine.accounts()?.collect();
+                        accounts
+                    });
+                    (sender, worker)
+                })
+                .unzip();
+            let mut read = Ok(());
+            let mut claimed = HashMap::new();
+            let mut skipped = vec![];
+            for txn in txns {
+                let txn = match txn {
+                    Ok(txn) => txn,
+                    Err(
+                        e @ (MalipoError::InvalidAmount { .. }
+                        | MalipoError::InvalidIdempotencyKey { .. }),
+                    ) => {
+                        skipped.push(e);
+                        continue;
+                    }
+                    Err(e) => {
+                        read = Err(e);
+                        break;
+                    }
+                };
+                let shard = usize::from(txn.client_id) % num_threads;
+                // A worker rejects duplicates among its own clients itself
+                if txn.type_.carries_amount() && *claimed.entry(txn.id).or_insert(shard) != shard {
+                    continue;
+                }
+                // A closed channel means the worker failed, its error is returned below
+                if senders[shard].send(txn).is_err() {
+                    break;
+                }
+            }
+            drop(senders);
+            let mut accounts = AccountsMemStore::new();
+            for worker in workers {
+                for acc in worker.join().expect("worker thread panicked")? {
+                    accounts.create(acc)?;
+                }
+            }
+            read?;
+            Ok((accounts, skipped))
+        })
+    }
+
+    /// Execute a batch of transactions in order.
+    ///
+    /// With `strict_ordering`, the batch is scanned first and nothing is
+    /// applied if a dispute arrives before the transaction it references.
+    pub fn execute_batch(&mut self, txns: &[Transaction]) -> Fallible<Vec<TransactionOutcome>> {
+        if self.config.strict_ordering {
+            let early = Self::prevalidate_disputes(txns.iter().copied())
+                .into_iter()
+                .find(|(_, _, issue)| *issue == InvalidReference::Later);
+            if let Some((_, tx, _)) = early {
+                return Err(MalipoError::DisputeBeforeDeposit { tx });
+            }
+        }
+        txns.iter()
+            .map(|txn| self.execute_transaction(*txn))
+            .collect()
+    }
+
+    /// Execute a transaction.
+    ///
+    /// The account and transaction writes are applied together: if any
+    /// store operation fails, both are restored to their prior state. A
+    /// transaction that is rejected or fails leaves no account behind for a
+    /// client that had none.
+    pub fn execute_transaction(&mut self, txn: Transaction) -> Fallible<TransactionOutcome> {
+        self.execute(txn, Mode::Normal)
+    }
+
+    /// Apply a deposit even if the account is frozen, for manual correction
+    /// under supervision. Everything else about the deposit is checked as
+    /// usual; any other transaction type is an error.
+    pub fn deposit_supervised(&mut self, txn: Transaction) -> Fallible<TransactionOutcome> {
+        if txn.type_ != TransactionType::Deposit {
+            return Err(MalipoError::InvalidTransaction {
+                tx: txn.id,
+                reason: format!("{:?} cannot be supervised", txn.type_),
+            });
+        }
+        self.execute(txn, Mode::Supervised)
+    }
+
+    /// Execute a transaction as `mode` says. A reversal skips the checks
+    /// for new transactions, which its stored transaction already passed.
+    fn execute(&mut self, txn: Transaction, mode: Mode) -> Fallible<TransactionOutcome> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "execute_transaction",
+            tx = txn.id,
+            client = txn.client_id,
+            type_ = ?txn.type_
+        )
+        .entered();
+        let appendable = matches!(
+            txn.type_,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+        let early = if self.config.append_only && (mode == Mode::Reversal || !appendable) {
+            trace_skip!("append-only mode, transaction skipped");
+            Some(RejectReason::AppendOnly)
+        } else if !self.sampled(txn.id) {
+            trace_skip!("not sampled, transaction skipped");
+            Some(RejectReason::NotSampled)
+        } else if self.account_limit_reached(txn.client_id)? {
+            trace_skip!("account limit reached, transaction skipped");
+            Some(RejectReason::AccountLimitReached)
+        } else {
+            None
+        };
+        if let Some(reason) = early {
+            let outcome = self.apply_policy(txn, TransactionOutcome::rejected(reason))?;
+            self.emit_audit(txn, Account::new(txn.client_id), outcome, mode)?;
+            self.record_outcome(txn, outcome, mode);
+            return Ok(outcome);
+        }
+        let acc_before = self.accounts.find(txn.client_id)?;
+        let txn_before = match self.transactions.get(txn.id) {
+            Ok(prev_txn) => Some(prev_txn),
+            Err(MalipoError::TransactionNotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        let stats_before = self.stats;
+        let audit_len = self.dispute_audit.len();
+        let noops_len = self.noops.len();
+        let key_seen = match txn.idempotency_key {
+            Some(key) if txn.type_.carries_amount() && mode != Mode::Reversal => {
+                self.key_seen(key)?
+            }
+            _ => false,
+        };
+        let frozen = acc_before.is_some_and(|acc| acc.is_frozen()) && mode != Mode::Supervised;
+        let reason = match mode {
+            Mode::Reversal if frozen => {
+                trace_skip!("account frozen, reversal skipped");
+                Some(RejectReason::AccountFrozen)
+            }
+            Mode::Reversal => None,
+            _ => Self::precheck(
+                &txn,
+                frozen,
+                txn_before.is_some(),
+                key_seen,
+                self.config.allow_zero_deposit_activation,
+            ),
+        };
+        let applied = match (reason, mode) {
+            (Some(reason), _) => Ok(TransactionOutcome::rejected(reason)),
+            (None, Mode::Reversal) => self.reverse(txn),
+            (None, _) => self.apply(txn),
+        };
+        let result = match applied {
+            Err(MalipoError::AmountOverflow) => {
+                trace_skip!("amount overflow, transaction skipped");
+                Ok(TransactionOutcome::rejected(RejectReason::AmountOverflow))
+            }
+            result => result,
+        };
+        let result = result.and_then(|outcome| {
+            let outcome = self.apply_policy(txn, outcome)?;
+            let before = acc_before.unwrap_or_else(|| Account::new(txn.client_id));
+            self.emit_audit(txn, before, outcome, mode)?;
+            Ok(outcome)
+        });
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                self.stats = stats_before;
+                self.dispute_audit.truncate(audit_len);
+                self.noops.truncate(noops_len);
+                self.restore_account(txn.client_id, acc_before)?;
+                match txn_before {
+                    Some(prev_txn) => self.transactions.update(prev_txn)?,
+                    None => self.transactions.delete(txn.id)?,
+                }
+                return Err(e);
+            }
+        };
+        if acc_before.is_none() {
+            match outcome {
+                TransactionOutcome::Applied => {
+                    if let Some(count) = self.account_count.as_mut() {
+                        *count += 1;
+                    }
+                }
+                _ => self.accounts.delete(txn.client_id)?,
+            }
+        }
+        self.record_outcome(txn, outcome, mode);
+        Ok(outcome)
+    }
+
+    /// Whether a deposit, withdrawal or authorization with `key` was already
+    /// applied. The keys are loaded from the stored transactions on first
+    /// use, so a reopened store keeps rejecting keys applied before.
+    fn key_seen(&mut self, key: IdempotencyKey) -> Fallible<bool> {
+        if self.idempotency_keys.is_none() {
+            let keys = self
+                .transactions
+                .iter()?
+                .filter_map(|txn| txn.map(|txn| txn.idempotency_key).transpose())
+                .collect::<Fallible<_>>()?;
+            self.idempotency_keys = Some(keys);
+        }
+        Ok(self
+            .idempotency_keys
+            .as_ref()
+            .is_some_and(|keys| keys.contains(&key)))
+    }
+
+    /// Turn a rejection into an error if its reason's policy is to abort
+    fn apply_policy(
+        &self,
+        txn: Transaction,
+        outcome: TransactionOutcome,
+    ) -> Fallible<TransactionOutcome> {
+        let TransactionOutcome::Rejected { reason } = outcome else {
+            return Ok(outcome);
+        };
+        let policy = self.config.error_policy.get(&reason).copied();
+        match policy.unwrap_or_default() {
+            ErrorPolicy::Abort => Err(MalipoError::TransactionRejected { tx: txn.id, reason }),
+            ErrorPolicy::Skip => Ok(outcome),
+            ErrorPolicy::Warn => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(tx = txn.id, ?reason, "transaction rejected");
+                Ok(outcome)
+            }
+        }
+    }
+
+    /// Put back the account a client had before a transaction, deleting the
+    /// account if the client had none
+    fn restore_account(&mut self, client_id: ClientId, before: Option<Account>) -> Fallible<()> {
+        match before {
+            Some(acc) => self.accounts.update(acc),
+            None => self.accounts.delete(client_id),
+        }
+    }
+
+    /// Whether the transaction `tx` falls in the configured sample
+    fn sampled(&self, tx: TransactionId) -> bool {
+        let rate = match self.config.sample_rate {
+            Some(rate) => rate,
+            None => return true,
+        };
+        // splitmix64 finalizer, spreading IDs evenly over the unit interval
+        let mut z = self.config.seed ^ u64::from(tx);
+        z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }
+
+    /// Whether `client_id` would be a new account beyond `max_accounts`.
+    /// Accounts are counted once, then the count is kept as accounts are
+    /// created and merged.
+    fn account_limit_reached(&mut self, client_id: ClientId) -> Fallible<bool> {
+        let max = match self.config.max_accounts {
+            Some(max) => max,
+            None => return Ok(false),
+        };
+        if self.accounts.find(client_id)?.is_some() {
+            return Ok(false);
+        }
+        let count = match self.account_count {
+            Some(count) => count,
+            None => {
+                let count = self.accounts.ids()?.collect::<Fallible<Vec<_>>>()?.len();
+                *self.account_count.insert(count)
+            }
+        };
+        Ok(count >= max)
+    }
+
+    /// Count the transaction as processed, then hand it to the sink and run
+    /// due maintenance if it was applied, or count it as rejected and keep it
+    /// while fewer than `max_rejected` are kept. An applied reversal takes
+    /// its transaction out of the gross sums and reaches the sink in its
+    /// reversed state.
+    fn record_outcome(&mut self, txn: Transaction, outcome: TransactionOutcome, mode: Mode) {
+        let index = self.processed;
+        self.processed += 1;
+        match outcome {
+            TransactionOutcome::Applied => {
+                self.last_activity.insert(txn.client_id, index);
+                let txn = match mode {
+                    Mode::Reversal => {
+                        self.track_position(txn, -1);
+                        txn.with_state(TxnState::Reversed)
+                    }
+                    _ => {
+                        self.track_position(txn, 1);
+                        txn
+                    }
+                };
+                if let (Some(keys), Some(key)) =
+                    (self.idempotency_keys.as_mut(), txn.idempotency_key)
+                {
+                    if txn.type_.carries_amount() {
+                        keys.insert(key);
+                    }
+                }
+                if let Some(sink) = self.config.applied_sink.as_mut() {
+                    sink(&txn);
+                }
+                self.applied += 1;
+                self.run_maintenance();
+            }
+            TransactionOutcome::Rejected { reason } => {
+                *self
+                    .rejection_counts
+                    .entry((txn.type_, reason))
+                    .or_default() += 1;
+                let full = self
+                    .config
+                    .max_rejected
+                    .is_some_and(|max| self.rejected.len() >= max);
+                if !full {
+                    self.rejected.push(RejectedTransaction {
+                        type_: txn.type_,
+                        client_id: txn.client_id,
+                        id: txn.id,
+                        reason,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Add a deposit or withdrawal to its client's gross sums, or take it
+    /// away for a `sign` of -1
+    fn track_position(&mut self, txn: Transaction, sign: i64) {
+        let amount = Amount::from_raw(sign * txn.amount.map_or(0, Amount::raw));
+        let (deposited, withdrawn) = match txn.type_ {
+            TransactionType::Deposit => (amount, Amount::ZERO),
+            TransactionType::Withdrawal => (Amount::ZERO, amount),
+            _ => return,
+        };
+        let position = self.positions.entry(txn.client_id).or_default();
+        position.0 += deposited;
+        position.1 += withdrawn;
+    }
+
+    /// Run the maintenance hook if the applied count reached its interval.
+    /// The hook is taken out while it runs, so it may use the engine freely.
+    fn run_maintenance(&mut self) {
+        let due = self
+            .config
+            .maintenance_interval
+            .is_some_and(|interval| self.applied.is_multiple_of(interval));
+        if !due {
+            return;
+        }
+        if let Some(mut hook) = self.config.maintenance_hook.take() {
+            hook(self);
+            self.config.maintenance_hook.get_or_insert(hook);
+        }
+    }
+
+    /// Why a transaction must be skipped before touching the stores, if it must
+    fn precheck(
+        txn: &Transaction,
+        frozen: bool,
+        exists: bool,
+        key_seen: bool,
+        allow_zero_deposit: bool,
+    ) -> Option<RejectReason> {
+        let valid = if allow_zero_deposit {
+            txn.validate_activation()
+        } else {
+            txn.validate()
+        };
+        if valid.is_err() {
+            trace_skip!("amount invalid for the transaction type, transaction skipped");
+            Some(RejectReason::InvalidAmount)
+        } else if key_seen {
+            trace_skip!("duplicate idempotency key, transaction skipped");
+            Some(RejectReason::DuplicateIdempotencyKey)
+        } else if !txn.type_.carries_amount() {
+            None
+        } else if exists {
+            trace_skip!("duplicate transaction id, transaction skipped");
+            Some(RejectReason::DuplicateTransaction)
+        } else if frozen {
+            trace_skip!("account frozen, transaction skipped");
+            Some(RejectReason::AccountFrozen)
+        } else {
+            None
+        }
+    }
+
+    /// Send the event for an executed transaction to the audit log
+    fn emit_audit(
+        &mut self,
+        txn: Transaction,
+        before: Account,
+        outcome: TransactionOutcome,
+        mode: Mode,
+    ) -> Fallible<()> {
+        if self.config.audit_log.is_none() {
+            return Ok(());
+        }
+        let event = match (outcome, mode) {
+            (TransactionOutcome::Applied, Mode::Reversal) => AuditEvent::Reversed {
+                txn,
+                before,
+                after: self.accounts.get(txn.client_id)?,
+            },
+            (TransactionOutcome::Applied, _) => AuditEvent::Applied {
+                txn,
+                before,
+                after: self.accounts.get(txn.client_id)?,
+            },
+            (TransactionOutcome::Rejected { reason }, _) => AuditEvent::skipped(txn, reason),
+        };
+        match self.config.audit_log.as_mut() {
+            Some(log) => log.on_event(&event),
+            None => Ok(()),
+        }
+    }
+
+    /// Undo a stored deposit or withdrawal. Taking back a deposit is checked
+    /// like a withdrawal, and giving back a withdrawal like a deposit.
+    fn reverse(&mut self, mut txn: Transaction) -> Fallible<TransactionOutcome> {
+        let reversible = matches!(
+            txn.type_,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+        if !reversible || txn.reverse().is_err() {
+            trace_skip!("transaction not reversible, reversal skipped");
+            return Ok(TransactionOutcome::rejected(RejectReason::NotReversible));
+        }
+        let amount = txn.amount.unwrap();
+        let mut acc = self.accounts.get(txn.client_id)?;
+        if txn.type_ == TransactionType::Deposit {
+            match acc.withdraw(amount) {
+                Ok(_) => self.stats.deposited -= amount,
+                Err(MalipoError::InsufficientAccountFunds) => {
+                    trace_skip!("insufficient funds, reversal skipped");
+                    return Ok(TransactionOutcome::rejected(
+                        RejectReason::InsufficientFunds,
+                    ));
+                }
+                Err(e) => return Err(e),
+            }
+        } else {
+            acc.deposit(amount)?;
+            self.stats.withdrawn -= amount;
+        }
+        self.accounts.update(acc)?;
+        self.transactions.update(txn)?;
+        Ok(TransactionOutcome::Applied)
+    }
+
+    /// Apply a transaction to the stores
+    fn apply(&mut self, txn: Transaction) -> Fallible<TransactionOutcome> {
         match txn.type_ {
-            TransactionType::Chargeback => self.chargeback(txn)?,
-            TransactionType::Deposit => self.deposit(txn)?,
-            TransactionType::Dispute => self.dispute(txn)?,
-            TransactionType::Resolve => self.resolve(txn)?,
-            TransactionType::Withdrawal => self.withdrawal(txn)?,
+            TransactionType::Authorize => self.authorize(txn),
+            TransactionType::Capture => self.capture(txn),
+            TransactionType::Chargeback => self.chargeback(txn),
+            TransactionType::Deposit => self.deposit(txn),
+            TransactionType::Dispute => self.dispute(txn),
+            TransactionType::Resolve => self.resolve(txn),
+            TransactionType::Void => self.void(txn),
+            TransactionType::Withdrawal => self.withdrawal(txn),
         }
-        Ok(())
     }
 
     /// Get a stream if accounts from the store
-    pub fn accounts(&self) -> Fallible<Box<dyn Iterator<Item = Account> + '_>> {
+    pub fn accounts(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Account>> + '_>> {
         self.accounts.iter()
     }
 
+    /// The stored account of a client, `None` if the client was never seen.
+    /// Unlike the store's `get`, this never creates an account.
+    pub fn account(&self, client_id: ClientId) -> Fallible<Option<Account>> {
+        self.accounts.find(client_id)
+    }
+
+    /// The stored transaction with ID `tx`, `None` if there is none. Like
+    /// `account`, this only reads the store.
+    pub fn get_transaction(&self, tx: TransactionId) -> Fallible<Option<Transaction>> {
+        self.transactions.find(tx)
+    }
+
+    /// The stored transactions of a client
+    pub fn transactions_for(&self, client_id: ClientId) -> Fallible<Vec<Transaction>> {
+        self.transactions
+            .iter()?
+            .filter(|txn| txn.as_ref().map_or(true, |txn| txn.client_id == client_id))
+            .collect()
+    }
+
+    /// IDs of all clients with an account, in ascending order. Reads only
+    /// the store's keys rather than whole accounts.
+    pub fn client_ids(&self) -> Fallible<Vec<ClientId>> {
+        let mut ids = self.accounts.ids()?.collect::<Fallible<Vec<_>>>()?;
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Totals of the funds moved so far
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Transactions rejected so far, in input order, up to `max_rejected`
+    pub fn rejected(&self) -> &[RejectedTransaction] {
+        &self.rejected
+    }
+
+    /// Number of transactions rejected so far, per transaction type and
+    /// reason, including those past `max_rejected`
+    pub fn rejection_counts(&self) -> &HashMap<(TransactionType, RejectReason), usize> {
+        &self.rejection_counts
+    }
+
+    /// Per client, in ascending order, the sums of its applied deposits and
+    /// withdrawals. Disputes, resolves and chargebacks leave these gross
+    /// flows untouched, so a charged back deposit still counts as deposited.
+    pub fn net_positions(&self) -> Fallible<Vec<(ClientId, Amount, Amount)>> {
+        let mut positions: Vec<_> = self
+            .positions
+            .iter()
+            .map(|(&client, &(deposited, withdrawn))| (client, deposited, withdrawn))
+            .collect();
+        positions.sort_unstable_by_key(|(client, ..)| *client);
+        Ok(positions)
+    }
+
+    /// IDs of the transactions applied without changing their account's
+    /// funds, in input order: zero deposit activations and the disputes of
+    /// them, and resolves that find no held funds to release. Chargebacks
+    /// are never counted, as they lock the account.
+    pub fn noop_transactions(&self) -> &[TransactionId] {
+        &self.noops
+    }
+
+    /// Per client, the processing index of its last applied transaction,
+    /// counting from zero over every transaction executed, applied or not
+    pub fn last_activity(&self) -> &HashMap<ClientId, usize> {
+        &self.last_activity
+    }
+
+    /// Whether `client` could currently dispute the transaction `tx`: it
+    /// exists, belongs to the client, is a deposit or withdrawal that is not
+    /// already disputed, charged back or reversed, and the account is not
+    /// frozen
+    pub fn is_disputable(&self, tx: TransactionId, client: ClientId) -> Fallible<bool> {
+        let mut txn = match self.transactions.find(tx)? {
+            Some(txn) => txn,
+            None => return Ok(false),
+        };
+        let disputable_type = match txn.type_ {
+            TransactionType::Deposit => true,
+            TransactionType::Withdrawal => self.config.allow_withdrawal_disputes,
+            _ => false,
+        };
+        let frozen = self.account(client)?.is_some_and(|acc| acc.is_frozen());
+        Ok(txn.client_id == client && disputable_type && !frozen && txn.dispute().is_ok())
+    }
+
+    /// Clients whose accounts were affected by the transaction `tx`, empty
+    /// when no such transaction is stored. Every transaction type currently
+    /// touches a single client.
+    pub fn accounts_for_transaction(&self, tx: TransactionId) -> Fallible<Vec<ClientId>> {
+        let txn = self.transactions.find(tx)?;
+        Ok(txn.map(|txn| txn.client_id).into_iter().collect())
+    }
+
+    /// Difference between the funds held in accounts and the funds that
+    /// entered and left the engine. Zero when no funds have leaked.
+    pub fn conservation_delta(&self) -> Fallible<Amount> {
+        let held = self
+            .accounts()?
+            .map(|acc| acc.map(|acc| acc.total()))
+            .sum::<Fallible<Amount>>()?;
+        let moved = self.stats.deposited - self.stats.withdrawn - self.stats.charged_back;
+        Ok(held - moved)
+    }
+
+    /// Run the end of processing checks enabled in the configuration
+    pub fn finish(&self) -> Fallible<()> {
+        if self.config.verify_conservation {
+            let delta = self.conservation_delta()?;
+            if delta != Amount::ZERO {
+                return Err(MalipoError::ConservationViolation { delta });
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge the account of client `merge` into that of client `keep`.
+    /// Balances are summed, the result is locked if either account was, and
+    /// the merged client's transactions are reassigned to `keep`, whose
+    /// account is created if it has none. Fails with `AppendOnly` in
+    /// append-only mode.
+    pub fn merge_clients(&mut self, keep: ClientId, merge: ClientId) -> Fallible<()> {
+        if self.config.append_only {
+            return Err(MalipoError::AppendOnly);
+        }
+        if keep == merge {
+            return Ok(());
+        }
+        let merged = self
+            .account(merge)?
+            .ok_or(MalipoError::AccountNotFound(merge))?;
+        let kept = self.accounts.find(keep)?.is_some();
+        let mut acc = self.accounts.get(keep)?;
+        acc.merge(&merged)?;
+        for mut txn in self.transactions_for(merge)? {
+            txn.client_id = keep;
+            self.transactions.update(txn)?;
+        }
+        if let Some((deposited, withdrawn)) = self.positions.remove(&merge) {
+            let position = self.positions.entry(keep).or_default();
+            position.0 += deposited;
+            position.1 += withdrawn;
+        }
+        if let Some(index) = self.last_activity.remove(&merge) {
+            let last = self.last_activity.entry(keep).or_insert(index);
+            *last = (*last).max(index);
+        }
+        self.accounts.update(acc)?;
+        self.accounts.delete(merge)?;
+        // Merging into a new account moves the merged one rather than
+        // freeing a slot
+        if let (true, Some(count)) = (kept, self.account_count.as_mut()) {
+            *count -= 1;
+        }
+        Ok(())
+    }
+
+    /// Reverse a past deposit or withdrawal, undoing its effect on the
+    /// account and marking it reversed so it can no longer be disputed.
+    /// Transactions under dispute, already charged back or of another type
+    /// are not reversible, a deposit whose funds are no longer available is
+    /// rejected as `InsufficientFunds`, frozen accounts are left untouched,
+    /// and nothing is reversed in append-only mode. The reversal is
+    /// committed, audited and recorded like any executed transaction.
+    pub fn reverse_transaction(&mut self, tx: TransactionId) -> Fallible<TransactionOutcome> {
+        match self.transactions.get(tx) {
+            Ok(txn) => self.execute(txn, Mode::Reversal),
+            Err(MalipoError::TransactionNotFound(_)) => Ok(TransactionOutcome::rejected(
+                RejectReason::UnknownTransaction,
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Undo `txns`, the transactions applied so far in the order they were
+    /// applied, by reversing the effect of each one from last to first.
+    /// Disputes, resolves and chargebacks restore the referenced transaction
+    /// to its earlier state, and a chargeback leaves the account locked only
+    /// if an earlier chargeback for the client remains. Fails with
+    /// `AppendOnly` in append-only mode.
+    pub fn unwind(&mut self, txns: &[Transaction]) -> Fallible<()> {
+        if self.config.append_only {
+            return Err(MalipoError::AppendOnly);
+        }
+        for (idx, txn) in txns.iter().enumerate().rev() {
+            let earlier = &txns[..idx];
+            let mut acc = self.accounts.get(txn.client_id)?;
+            let prev_txn = self.transactions.get(txn.id)?;
+            let amount = prev_txn.amount.unwrap_or(Amount::ZERO);
+            let withdrawal = prev_txn.type_ == TransactionType::Withdrawal;
+            let zero = Amount::ZERO;
+            // Effect of the transaction on available, held and total funds
+            let (available, held, total) = match txn.type_ {
+                TransactionType::Deposit => {
+                    self.stats.deposited -= amount;
+                    (amount, zero, amount)
+                }
+                TransactionType::Withdrawal => {
+                    self.stats.withdrawn -= amount;
+                    (-amount, zero, -amount)
+                }
+                TransactionType::Authorize => (-amount, amount, zero),
+                TransactionType::Capture => {
+                    self.stats.withdrawn -= amount;
+                    (zero, -amount, -amount)
+                }
+                TransactionType::Void => (amount, -amount, zero),
+                TransactionType::Dispute if withdrawal => {
+                    self.stats.withdrawn += amount;
+                    (zero, amount, amount)
+                }
+                TransactionType::Dispute => (-amount, amount, zero),
+                TransactionType::Resolve if withdrawal => {
+                    self.stats.withdrawn -= amount;
+                    (zero, -amount, -amount)
+                }
+                TransactionType::Resolve => (amount, -amount, zero),
+                TransactionType::Chargeback if withdrawal => (amount, -amount, zero),
+                TransactionType::Chargeback => {
+                    self.stats.charged_back -= amount;
+                    (zero, -amount, -amount)
+                }
+            };
+            acc.adjust(-available, -held, -total)?;
+            if txn.type_ == TransactionType::Chargeback {
+                acc.set_locked(earlier.iter().any(|prev| {
+                    prev.type_ == TransactionType::Chargeback && prev.client_id == txn.client_id
+                }));
+            }
+            self.accounts.update(acc)?;
+            if let (Some(keys), Some(key)) = (self.idempotency_keys.as_mut(), txn.idempotency_key) {
+                keys.remove(&key);
+            }
+            self.track_position(*txn, -1);
+
+            let restored = match txn.type_ {
+                TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Authorize => {
+                    self.transactions.delete(txn.id)?;
+                    continue;
+                }
+                TransactionType::Capture | TransactionType::Void => TxnState::Authorized,
+                TransactionType::Resolve | TransactionType::Chargeback => TxnState::Disputed,
+                TransactionType::Dispute => {
+                    let resolved_before = earlier
+                        .iter()
+                        .any(|prev| prev.type_ == TransactionType::Resolve && prev.id == txn.id);
+                    match resolved_before {
+                        true => TxnState::Resolved,
+                        false => TxnState::Normal,
+                    }
+                }
+            };
+            self.transactions.update(prev_txn.with_state(restored))?;
+        }
+        Ok(())
+    }
+
+    /// Check, without applying anything, that every dispute, resolve and
+    /// chargeback references an earlier deposit or withdrawal of the same
+    /// client. Returns the problematic references in input order.
+    pub fn prevalidate_disputes<I: IntoIterator<Item = Transaction>>(
+        txns: I,
+    ) -> Vec<(TransactionType, TransactionId, InvalidReference)> {
+        let mut seen = HashMap::new();
+        let mut issues = Vec::new();
+        for txn in txns {
+            match txn.type_ {
+                TransactionType::Deposit | TransactionType::Withdrawal => {
+                    seen.entry(txn.id).or_insert(txn.client_id);
+                }
+                TransactionType::Dispute
+                | TransactionType::Resolve
+                | TransactionType::Chargeback => match seen.get(&txn.id) {
+                    Some(client_id) if *client_id == txn.client_id => {}
+                    Some(_) => {
+                        issues.push((txn.type_, txn.id, Some(InvalidReference::WrongClient)))
+                    }
+                    None => issues.push((txn.type_, txn.id, None)),
+                },
+                _ => {}
+            }
+        }
+        issues
+            .into_iter()
+            .map(|(type_, id, issue)| {
+                let issue = issue.unwrap_or(if seen.contains_key(&id) {
+                    InvalidReference::Later
+                } else {
+                    InvalidReference::Missing
+                });
+                (type_, id, issue)
+            })
+            .collect()
+    }
+
+    /// Write the accounts matching a predicate as CSV
+    pub fn write_filtered<W: std::io::Write, F: Fn(&Account) -> bool>(
+        &self,
+        pred: F,
+        wtr: W,
+    ) -> Fallible<()> {
+        let accounts = self
+            .accounts()?
+            .filter(|acc| acc.as_ref().map_or(true, &pred));
+        CsvWriterStdout::write(Box::new(accounts), Some(wtr))
+    }
+
+    /// Write the dispute audit trail as CSV, in the order the disputes,
+    /// resolves and chargebacks were applied
+    pub fn write_dispute_audit<W: std::io::Write>(&self, wtr: W) -> Fallible<()> {
+        let mut writer = csv::Writer::from_writer(wtr);
+        for entry in &self.dispute_audit {
+            writer.serialize(entry).map_err(MalipoError::CsvError)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Record a dispute, resolve or chargeback in the audit trail
+    fn audit_dispute(&mut self, txn: &Transaction, amount: Amount, acc: &Account) {
+        self.dispute_audit.push(DisputeAuditEntry {
+            id: txn.id,
+            client_id: txn.client_id,
+            action: txn.type_,
+            amount,
+            held_after: acc.held(),
+        });
+    }
+
+    /// Record `tx` as a no-op if it moved no funds
+    fn note_noop(&mut self, tx: TransactionId, moved: Amount) {
+        if moved == Amount::ZERO {
+            self.noops.push(tx);
+        }
+    }
+
     /// A deposit is a credit to the client's asset account, meaning it should
-    /// increase the available and total funds of the client account
-    fn deposit(&mut self, txn: Transaction) -> Fallible<()> {
+    /// increase the available and total funds of the client account. A
+    /// deposit that would overflow the account or the deposited total fails
+    /// with `AmountOverflow` before anything is written.
+    fn deposit(&mut self, txn: Transaction) -> Fallible<TransactionOutcome> {
+        let amount = txn.amount.unwrap();
+        let deposited = self
+            .stats
+            .deposited
+            .checked_add(amount)
+            .ok_or(MalipoError::AmountOverflow)?;
         let mut acc = self.accounts.get(txn.client_id)?;
-        acc.deposit(txn.amount.unwrap());
+        acc.deposit(amount)?;
         self.accounts.update(acc)?;
         self.transactions.create(txn)?;
-        Ok(())
+        self.stats.deposited = deposited;
+        self.note_noop(txn.id, amount);
+        Ok(TransactionOutcome::Applied)
     }
 
     /// A withdraw is a debit to the client's asset account, meaning it should
-    /// decrease the available and total funds of the client account
-    fn withdrawal(&mut self, txn: Transaction) -> Fallible<()> {
+    /// decrease the available and total funds of the client account. A
+    /// withdrawal exceeding the available funds is not stored.
+    fn withdrawal(&mut self, txn: Transaction) -> Fallible<TransactionOutcome> {
         let mut acc = self.accounts.get(txn.client_id)?;
         match acc.withdraw(txn.amount.unwrap()) {
-            Ok(_) => {}
-            Err(MalipoError::InsufficientAccountFunds) => {}
+            Ok(_) => self.stats.withdrawn += txn.amount.unwrap(),
+            Err(MalipoError::InsufficientAccountFunds) => {
+                trace_skip!("insufficient funds, withdrawal skipped");
+                return Ok(TransactionOutcome::rejected(
+                    RejectReason::InsufficientFunds,
+                ));
+            }
             Err(e) => return Err(e),
-        };
+        }
         self.accounts.update(acc)?;
         self.transactions.create(txn)?;
-        Ok(())
+        Ok(TransactionOutcome::Applied)
     }
 
     /// A chargeback is the final state of a dispute and represents the client
     /// reversing a transaction. Funds that were held have now been withdrawn.
     /// This means that the clients held funds and total funds should decrease
     /// by the amount previously disputed. If a chargeback occurs the client's
-    /// account should be immediately frozen
-    fn chargeback(&mut self, txn: Transaction) -> Fallible<()> {
+    /// account should be immediately frozen. Only a transaction currently
+    /// under dispute can be charged back; a resolved one must be disputed again.
+    fn chargeback(&mut self, txn: Transaction) -> Fallible<TransactionOutcome> {
         match self.transactions.get(txn.id) {
-            Ok(prev_txn) => {
-                if prev_txn.is_disputed() {
+            Ok(mut prev_txn) => {
+                if prev_txn.client_id != txn.client_id {
+                    trace_skip!("transaction belongs to another client, chargeback skipped");
+                    return Ok(TransactionOutcome::rejected(RejectReason::ClientMismatch));
+                }
+                if prev_txn.chargeback().is_ok() {
                     let mut acc = self.accounts.get(txn.client_id)?;
-                    acc.chargeback(prev_txn.amount.unwrap());
+                    let amount = prev_txn.amount.unwrap();
+                    if prev_txn.type_ == TransactionType::Withdrawal {
+                        acc.chargeback_withdrawal(amount);
+                    } else {
+                        acc.chargeback(amount);
+                        self.stats.charged_back += amount;
+                    }
                     self.accounts.update(acc)?;
+                    self.audit_dispute(&txn, amount, &acc);
+                    self.transactions.update(prev_txn)?;
+                    Ok(TransactionOutcome::Applied)
+                } else if self.config.strict_chargeback && prev_txn.state() == TxnState::Resolved {
+                    trace_skip!("dispute already resolved, chargeback skipped");
+                    Ok(TransactionOutcome::rejected(
+                        RejectReason::ChargebackAfterResolve,
+                    ))
+                } else {
+                    trace_skip!("transaction not disputed, chargeback skipped");
+                    Ok(TransactionOutcome::rejected(RejectReason::NotDisputed))
                 }
             }
-            Err(MalipoError::TransactionNotFound(_)) => {}
-            Err(e) => return Err(e),
+            Err(MalipoError::TransactionNotFound(_)) => {
+                trace_skip!("transaction not found, chargeback skipped");
+                Ok(TransactionOutcome::rejected(
+                    RejectReason::UnknownTransaction,
+                ))
+            }
+            Err(e) => Err(e),
         }
-        Ok(())
     }
 
     /// A dispute represents a client's claim that a transaction was erroneous
@@ -84,19 +1196,51 @@ impl PaymentsEngine {
     /// the associated funds should be held. This means that the clients available
     /// funds should decrease by the amount disputed, their held funds should
     /// increase by the amount disputed, while their total funds should remain the same.
-    fn dispute(&mut self, txn: Transaction) -> Fallible<()> {
+    /// A disputed withdrawal instead holds the withdrawn amount, increasing the
+    /// held and total funds while leaving the available funds untouched.
+    fn dispute(&mut self, txn: Transaction) -> Fallible<TransactionOutcome> {
         match self.transactions.get(txn.id) {
-            Err(MalipoError::TransactionNotFound(_)) => {}
-            Err(e) => return Err(e),
+            Err(MalipoError::TransactionNotFound(_)) => {
+                trace_skip!("transaction not found, dispute skipped");
+                Ok(TransactionOutcome::rejected(
+                    RejectReason::UnknownTransaction,
+                ))
+            }
+            Err(e) => Err(e),
             Ok(mut prev_txn) => {
+                if prev_txn.client_id != txn.client_id {
+                    trace_skip!("transaction belongs to another client, dispute skipped");
+                    return Ok(TransactionOutcome::rejected(RejectReason::ClientMismatch));
+                }
+                let withdrawal = prev_txn.type_ == TransactionType::Withdrawal;
+                if prev_txn.dispute().is_err()
+                    || (withdrawal && !self.config.allow_withdrawal_disputes)
+                {
+                    trace_skip!("transaction not disputable, dispute skipped");
+                    return Ok(TransactionOutcome::rejected(RejectReason::NotDisputable));
+                }
                 let mut acc = self.accounts.get(txn.client_id)?;
-                acc.dispute(prev_txn.amount.unwrap());
+                let amount = prev_txn.amount.unwrap();
+                if withdrawal {
+                    acc.dispute_withdrawal(amount);
+                    self.stats.withdrawn -= amount;
+                } else {
+                    acc.dispute(amount);
+                }
+                if self.config.bound_dispute_holds && acc.held() > acc.total() {
+                    return Err(MalipoError::DisputeExceedsAccount {
+                        tx: txn.id,
+                        held: acc.held(),
+                        total: acc.total(),
+                    });
+                }
                 self.accounts.update(acc)?;
-                prev_txn.mark_as_disputed();
+                self.audit_dispute(&txn, amount, &acc);
                 self.transactions.update(prev_txn)?;
+                self.note_noop(txn.id, amount);
+                Ok(TransactionOutcome::Applied)
             }
         }
-        Ok(())
     }
 
     /// A resolve represents a resolution to a dispute, releasing the associated
@@ -104,20 +1248,173 @@ impl PaymentsEngine {
     /// This means that the clients held funds should decrease by the amount no
     /// longer disputed, their available funds should increase by the amount no
     /// longer disputed, and their total funds should remain the same.
-    fn resolve(&mut self, txn: Transaction) -> Fallible<()> {
+    /// The release never exceeds the held funds. Resolving a disputed withdrawal
+    /// lets the withdrawal stand, so the held and total funds decrease instead.
+    fn resolve(&mut self, txn: Transaction) -> Fallible<TransactionOutcome> {
         match self.transactions.get(txn.id) {
             Ok(mut prev_txn) => {
-                if prev_txn.is_disputed() {
+                if prev_txn.client_id != txn.client_id {
+                    trace_skip!("transaction belongs to another client, resolve skipped");
+                    return Ok(TransactionOutcome::rejected(RejectReason::ClientMismatch));
+                }
+                if prev_txn.resolve().is_ok() {
                     let mut acc = self.accounts.get(txn.client_id)?;
-                    acc.resolve(prev_txn.amount.unwrap());
+                    let amount = prev_txn.amount.unwrap();
+                    if amount > acc.held() && self.config.error_on_over_resolve {
+                        return Err(MalipoError::InvariantViolation(format!(
+                            "resolve of {} releases {} but client {} holds {}",
+                            prev_txn.id,
+                            amount,
+                            acc.client_id,
+                            acc.held()
+                        )));
+                    }
+                    let release = amount.min(acc.held());
+                    self.note_noop(txn.id, release);
+                    if prev_txn.type_ == TransactionType::Withdrawal {
+                        acc.resolve_withdrawal(release);
+                        self.stats.withdrawn += release;
+                    } else {
+                        acc.resolve(release);
+                    }
                     self.accounts.update(acc)?;
-                    prev_txn.resolve_dispute();
+                    self.audit_dispute(&txn, release, &acc);
                     self.transactions.update(prev_txn)?;
+                    Ok(TransactionOutcome::Applied)
+                } else if self.config.strict_resolve && prev_txn.state() == TxnState::Resolved {
+                    trace_skip!("dispute already resolved, resolve skipped");
+                    Ok(TransactionOutcome::rejected(RejectReason::AlreadyResolved))
+                } else {
+                    trace_skip!("transaction not disputed, resolve skipped");
+                    Ok(TransactionOutcome::rejected(RejectReason::NotDisputed))
                 }
             }
-            Err(MalipoError::TransactionNotFound(_)) => {}
-            Err(e) => return Err(e),
+            Err(MalipoError::TransactionNotFound(_)) => {
+                trace_skip!("transaction not found, resolve skipped");
+                Ok(TransactionOutcome::rejected(
+                    RejectReason::UnknownTransaction,
+                ))
+            }
+            Err(e) => Err(e),
         }
-        Ok(())
+    }
+
+    /// An authorization holds funds for a later capture or void. The clients
+    /// available funds should decrease and their held funds should increase
+    /// by the amount authorized. Frozen accounts cannot authorize.
+    fn authorize(&mut self, mut txn: Transaction) -> Fallible<TransactionOutcome> {
+        let mut acc = self.accounts.get(txn.client_id)?;
+        match acc.authorize(txn.amount.unwrap()) {
+            Ok(_) => {
+                self.accounts.update(acc)?;
+                txn.authorize()?;
+                self.transactions.create(txn)?;
+                Ok(TransactionOutcome::Applied)
+            }
+            Err(MalipoError::InsufficientAccountFunds) => {
+                trace_skip!("insufficient funds, authorization skipped");
+                Ok(TransactionOutcome::rejected(
+                    RejectReason::InsufficientFunds,
+                ))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// A capture debits funds held by an authorization. The clients held
+    /// and total funds should decrease by the amount authorized.
+    fn capture(&mut self, txn: Transaction) -> Fallible<TransactionOutcome> {
+        match self.transactions.get(txn.id) {
+            Ok(mut prev_txn) => {
+                if prev_txn.client_id != txn.client_id {
+                    trace_skip!("transaction belongs to another client, capture skipped");
+                    return Ok(TransactionOutcome::rejected(RejectReason::ClientMismatch));
+                }
+                if prev_txn.capture().is_ok() {
+                    let mut acc = self.accounts.get(txn.client_id)?;
+                    acc.capture(prev_txn.amount.unwrap());
+                    self.accounts.update(acc)?;
+                    self.transactions.update(prev_txn)?;
+                    self.stats.withdrawn += prev_txn.amount.unwrap();
+                    Ok(TransactionOutcome::Applied)
+                } else {
+                    trace_skip!("transaction not authorized, capture skipped");
+                    Ok(TransactionOutcome::rejected(RejectReason::NotAuthorized))
+                }
+            }
+            Err(MalipoError::TransactionNotFound(_)) => {
+                trace_skip!("transaction not found, capture skipped");
+                Ok(TransactionOutcome::rejected(
+                    RejectReason::UnknownTransaction,
+                ))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// A void releases funds held by an authorization. The clients held
+    /// funds should decrease and their available funds should increase by
+    /// the amount authorized.
+    fn void(&mut self, txn: Transaction) -> Fallible<TransactionOutcome> {
+        match self.transactions.get(txn.id) {
+            Ok(mut prev_txn) => {
+                if prev_txn.client_id != txn.client_id {
+                    trace_skip!("transaction belongs to another client, void skipped");
+                    return Ok(TransactionOutcome::rejected(RejectReason::ClientMismatch));
+                }
+                if prev_txn.void().is_ok() {
+                    let mut acc = self.accounts.get(txn.client_id)?;
+                    acc.void(prev_txn.amount.unwrap());
+                    self.accounts.update(acc)?;
+                    self.transactions.update(prev_txn)?;
+                    Ok(TransactionOutcome::Applied)
+                } else {
+                    trace_skip!("transaction not authorized, void skipped");
+                    Ok(TransactionOutcome::rejected(RejectReason::NotAuthorized))
+                }
+            }
+            Err(MalipoError::TransactionNotFound(_)) => {
+                trace_skip!("transaction not found, void skipped");
+                Ok(TransactionOutcome::rejected(
+                    RejectReason::UnknownTransaction,
+                ))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Runs a stream of transactions through an engine one transaction at a time
+pub struct StepRunner<I> {
+    engine: PaymentsEngine,
+    txns: I,
+}
+
+impl<I: Iterator<Item = Fallible<Transaction>>> StepRunner<I> {
+    /// Create a runner over an engine and a transaction stream
+    pub fn new(engine: PaymentsEngine, txns: I) -> Self {
+        Self { engine, txns }
+    }
+
+    /// Apply the next transaction. Returns `None` once the stream is exhausted.
+    pub fn step(&mut self) -> Fallible<Option<(Transaction, TransactionOutcome)>> {
+        match self.txns.next() {
+            None => Ok(None),
+            Some(txn) => {
+                let txn = txn?;
+                let outcome = self.engine.execute_transaction(txn)?;
+                Ok(Some((txn, outcome)))
+            }
+        }
+    }
+
+    /// The engine, in its state after the last step
+    pub fn engine(&self) -> &PaymentsEngine {
+        &self.engine
+    }
+
+    /// Stop stepping and take back the engine
+    pub fn into_engine(self) -> PaymentsEngine {
+        self.engine
     }
 }
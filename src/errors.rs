@@ -1,10 +1,20 @@
 use thiserror::Error;
 
-use crate::domain::{ClientId, TransactionId};
+use crate::domain::{Amount, ClientId, RejectReason, TransactionId, TxnState};
 
 /// A result where the error channel is MalipoError
 pub type Fallible<T> = Result<T, MalipoError>;
 
+#[derive(Debug, Error)]
+#[error("Invalid amount: {0}")]
+/// A string that is not a valid four decimal place amount
+pub struct ParseAmountError(pub String);
+
+#[derive(Debug, Error)]
+#[error("Invalid idempotency key: {0}")]
+/// A string that is empty or too long to be an idempotency key
+pub struct ParseIdempotencyKeyError(pub String);
+
 #[derive(Debug, Error)]
 /// All possible Malipo errors
 pub enum MalipoError {
@@ -16,14 +26,123 @@ pub enum MalipoError {
     #[error("Transaction not found for id: {0}")]
     TransactionNotFound(TransactionId),
 
+    /// Transaction cannot move between these lifecycle states
+    #[error("Transaction {tx} cannot move from {from:?} to {to:?}")]
+    InvalidStateTransition {
+        /// Transaction ID
+        tx: TransactionId,
+        /// Current state
+        from: TxnState,
+        /// Requested state
+        to: TxnState,
+    },
+
+    /// Dispute row precedes the transaction it references
+    #[error("Dispute for transaction {tx} arrives before the transaction")]
+    DisputeBeforeDeposit {
+        /// Referenced transaction ID
+        tx: TransactionId,
+    },
+
+    /// Dispute would hold more than the account's total funds
+    #[error("Dispute of {tx} holds {held} but the account totals {total}")]
+    DisputeExceedsAccount {
+        /// Disputed transaction ID
+        tx: TransactionId,
+        /// Held funds after the dispute
+        held: Amount,
+        /// Total funds after the dispute
+        total: Amount,
+    },
+
     /// Insufficient Funds
     #[error("Insufficient funds in account")]
     InsufficientAccountFunds,
 
+    /// Amount too large to add to a balance or total
+    #[error("Amount out of range")]
+    AmountOverflow,
+
+    /// Malformed amount in the input data
+    #[error("Invalid amount on line {line}: {value}")]
+    InvalidAmount {
+        /// Line number of the offending record
+        line: u64,
+        /// Raw amount value as read from the input
+        value: String,
+    },
+
+    /// Malformed idempotency key in the input data
+    #[error("Invalid idempotency key on line {line}: {value}")]
+    InvalidIdempotencyKey {
+        /// Line number of the offending record
+        line: u64,
+        /// Raw key as read from the input
+        value: String,
+    },
+
+    /// Row with a different number of fields than the header
+    #[error("Expected {expected} fields on line {line}, found {found}")]
+    ColumnCount {
+        /// Line number of the offending record
+        line: u64,
+        /// Number of header fields
+        expected: usize,
+        /// Number of fields in the record
+        found: usize,
+    },
+
+    /// Client ID too large for the ClientId type
+    #[error("Client id {value} is out of range, the maximum is {max}")]
+    ClientIdOutOfRange {
+        /// Client ID as read from the input
+        value: String,
+        /// Largest supported client ID
+        max: u32,
+    },
+
+    /// Transaction breaks the rules for its type
+    #[error("Invalid transaction {tx}: {reason}")]
+    InvalidTransaction {
+        /// Transaction ID
+        tx: TransactionId,
+        /// Rule that was broken
+        reason: String,
+    },
+
+    /// Transaction rejected for a reason configured to abort processing
+    #[error("Transaction {tx} rejected: {reason:?}")]
+    TransactionRejected {
+        /// Transaction ID
+        tx: TransactionId,
+        /// Why the transaction was rejected
+        reason: RejectReason,
+    },
+
+    /// Recorded transactions cannot be changed in append-only mode
+    #[error("Recorded transactions cannot be changed in append-only mode")]
+    AppendOnly,
+
+    /// Account left in an inconsistent state
+    #[error("Account invariant violated: {0}")]
+    InvariantViolation(String),
+
+    /// Account totals do not match the funds moved by the engine
+    #[error("Funds not conserved, delta: {delta}")]
+    ConservationViolation {
+        /// Account totals minus the net funds moved
+        delta: Amount,
+    },
+
     /// CSV Data Error
     #[error("Error when processing CSV data: {0}")]
     CsvError(csv::Error),
 
+    /// SQLite store error
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+
     /// IO Errors
     #[error(transparent)]
     IoError(#[from] std::io::Error),
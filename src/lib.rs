@@ -3,9 +3,22 @@
 mod domain;
 mod engine;
 mod errors;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 mod store;
 
 pub use crate::domain::*;
-pub use crate::errors::{Fallible, MalipoError};
-pub use crate::store::{AccountsMemStore, CsvDataReader, CsvWriterStdout, TransactionsMemStore};
-pub use engine::PaymentsEngine;
+pub use crate::errors::{Fallible, MalipoError, ParseAmountError, ParseIdempotencyKeyError};
+pub use crate::store::{
+    AccountWriter, AccountsMemStore, AmountParser, CsvDataReader, CsvReaderConfig, CsvWriterConfig,
+    CsvWriterStdout, DedupById, DefaultAmountParser, InstrumentedStore, JsonLinesAuditLog,
+    JsonLinesEventLog, JsonWriter, LineTerminator, RawRecords, RotatingWriter, Rotation,
+    SpillingTransactionsStore, StoreCounter, StoreCounts, TableWriter, TransactionStreamExt,
+    TransactionsMemStore,
+};
+pub use engine::{
+    AuditLog, EngineConfig, ErrorPolicy, MaintenanceHook, PaymentsEngine, ProcessReport, Stats,
+    StepRunner, TransactionSink,
+};
+#[cfg(feature = "sqlite")]
+pub use sqlite::{SqliteAccountsStore, SqliteTransactionsStore};
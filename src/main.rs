@@ -1,7 +1,7 @@
-use clap::{crate_authors, crate_description, crate_name, crate_version, App, Arg};
+use clap::{crate_authors, crate_description, crate_name, crate_version, value_t, App, Arg};
 use malipo::{
-    AccountsMemStore, CsvDataReader, CsvWriterStdout, Fallible, PaymentsEngine,
-    TransactionsMemStore,
+    AccountWriter, CsvDataReader, CsvWriterStdout, Fallible, JsonWriter, PaymentsEngine, Store,
+    TableWriter,
 };
 
 fn main() -> Fallible<()> {
@@ -11,30 +11,164 @@ fn main() -> Fallible<()> {
         .about(crate_description!())
         .arg(
             Arg::with_name("INPUT")
-                .help("Sets the input file to use")
+                .help("Sets the input file to use, or - for stdin")
                 .required(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("N")
+                .help("Processes clients in parallel on N threads")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Sets the output format")
+                .possible_values(&["csv", "json", "table"])
+                .default_value("csv"),
+        )
         .get_matches();
     let input_fname = matches.value_of("INPUT").unwrap();
-    let transactions = CsvDataReader::new(input_fname)?;
-
-    let acc_store = Box::new(AccountsMemStore::new());
-    let txn_store = Box::new(TransactionsMemStore::new());
-    let mut engine = PaymentsEngine::new(acc_store, txn_store);
-    for txn in transactions {
-        engine.execute_transaction(txn?)?;
+    let threads = match matches.is_present("threads") {
+        true => value_t!(matches, "threads", usize).unwrap_or_else(|e| e.exit()),
+        false => 1,
+    };
+    let writer: &dyn AccountWriter = match matches.value_of("format") {
+        Some("json") => &JsonWriter,
+        Some("table") => &TableWriter,
+        _ => &CsvWriterStdout,
+    };
+    if threads > 1 {
+        let (accounts, skipped) = match input_fname {
+            "-" => PaymentsEngine::execute_parallel(
+                CsvDataReader::from_reader(std::io::stdin()),
+                threads,
+            )?,
+            path => PaymentsEngine::execute_parallel(CsvDataReader::new(path)?, threads)?,
+        };
+        for e in skipped {
+            eprintln!("skipping row: {}", e);
+        }
+        writer.write(accounts.iter()?, &mut std::io::stdout())?;
+        return Ok(());
+    }
+    let report = match input_fname {
+        "-" => PaymentsEngine::process_reader(
+            CsvDataReader::from_reader(std::io::stdin()),
+            None,
+            writer,
+        )?,
+        path => PaymentsEngine::process_file(path, None, writer)?,
+    };
+    for e in report.skipped {
+        eprintln!("skipping row: {}", e);
+    }
+    if report.withdrawals_rejected > 0 {
+        eprintln!("{} withdrawals rejected", report.withdrawals_rejected);
     }
-    CsvWriterStdout::write(engine.accounts()?, Some(std::io::stdout()))?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Write;
+    use malipo::{
+        Account, AccountsMemStore, Amount, AmountParser, AuditEvent, AuditLog, ClientId,
+        CsvDataReader, CsvReaderConfig, CsvWriterConfig, CsvWriterStdout, DefaultAmountParser,
+        EngineConfig, ErrorPolicy, IdempotencyKey, InstrumentedStore, InvalidReference,
+        JsonLinesAuditLog, JsonLinesEventLog, LineTerminator, MalipoError, ParseAmountError,
+        RejectReason, RejectedTransaction, RotatingWriter, Rotation, SpillingTransactionsStore,
+        StepRunner, Store, StoreCounts, Transaction, TransactionId, TransactionOutcome,
+        TransactionStreamExt, TransactionType, TransactionsMemStore, TxnState,
+    };
+    use std::collections::HashMap;
+    use std::io::{Seek, SeekFrom, Write};
     use tempfile::NamedTempFile;
 
+    fn reader(input: &str) -> Fallible<CsvDataReader> {
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(input.as_bytes())?;
+        CsvDataReader::new(input_file.path().to_str().unwrap())
+    }
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn mem_engine() -> PaymentsEngine {
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        PaymentsEngine::new(acc_store, txn_store)
+    }
+
+    /// Assert that two engines hold the same accounts, transactions and stats
+    fn assert_engines_equal(left: &PaymentsEngine, right: &PaymentsEngine) -> Fallible<()> {
+        let sorted_accounts = |engine: &PaymentsEngine| -> Fallible<Vec<Account>> {
+            let mut accounts: Vec<_> = engine.accounts()?.collect::<Fallible<_>>()?;
+            accounts.sort_by_key(|acc| acc.client_id);
+            Ok(accounts)
+        };
+        let accounts = sorted_accounts(left)?;
+        assert_eq!(accounts, sorted_accounts(right)?);
+        for acc in accounts {
+            let mut left_txns = left.transactions_for(acc.client_id)?;
+            let mut right_txns = right.transactions_for(acc.client_id)?;
+            left_txns.sort_by_key(|txn| txn.id);
+            right_txns.sort_by_key(|txn| txn.id);
+            assert_eq!(left_txns, right_txns, "client {}", acc.client_id);
+        }
+        let (left, right) = (left.stats(), right.stats());
+        assert_eq!(left.deposited, right.deposited);
+        assert_eq!(left.withdrawn, right.withdrawn);
+        assert_eq!(left.charged_back, right.charged_back);
+        Ok(())
+    }
+
+    /// Accounts store that credits an extra unit on every update
+    struct LeakyAccounts(AccountsMemStore);
+    impl Store<ClientId, Account> for LeakyAccounts {
+        fn create(&mut self, item: Account) -> Fallible<()> {
+            self.0.create(item)
+        }
+        fn delete(&mut self, id: ClientId) -> Fallible<()> {
+            self.0.delete(id)
+        }
+        fn get(&mut self, id: ClientId) -> Fallible<Account> {
+            self.0.get(id)
+        }
+        fn update(&mut self, mut item: Account) -> Fallible<()> {
+            item.deposit(amount("1.0"))?;
+            self.0.update(item)
+        }
+        fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Account>> + '_>> {
+            self.0.iter()
+        }
+    }
+
+    /// Accounts store that silently releases held funds on every update
+    struct ForgetfulAccounts(AccountsMemStore);
+    impl Store<ClientId, Account> for ForgetfulAccounts {
+        fn create(&mut self, item: Account) -> Fallible<()> {
+            self.0.create(item)
+        }
+        fn delete(&mut self, id: ClientId) -> Fallible<()> {
+            self.0.delete(id)
+        }
+        fn get(&mut self, id: ClientId) -> Fallible<Account> {
+            self.0.get(id)
+        }
+        fn update(&mut self, mut item: Account) -> Fallible<()> {
+            item.resolve(item.held());
+            self.0.update(item)
+        }
+        fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Account>> + '_>> {
+            self.0.iter()
+        }
+    }
+
     macro_rules! tst {
         ($name:ident, $input:expr, $expected:expr) => {
             #[test]
@@ -75,6 +209,18 @@ mod tests {
         "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,true\n"
     );
 
+    tst!(
+        test_double_dispute,
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1\ndispute,1,1",
+        "client,available,held,total,locked\n1,0.0000,10.0000,10.0000,false\n"
+    );
+
+    tst!(
+        test_double_chargeback,
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5.0\ndispute,1,1\nchargeback,1,1\nchargeback,1,1",
+        "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,true\n"
+    );
+
     tst!(
        test_resolution,
        "type,client,tx,amount\ndeposit,1,1,100.1\ndispute,1,1\ndeposit,2,12,1.77\ndispute,2,12\nresolve,2,12\nresolve,2,12\nresolve,2,12\nresolve,2,12",
@@ -92,4 +238,2405 @@ mod tests {
       withdrawal,2,4,3.0",
         "client,available,held,total,locked\n1,-0.5000,2.0000,1.5000,false\n2,2.0000,0.0000,2.0000,false\n"
     );
+
+    tst!(
+        test_authorize_capture,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nauthorize,1,2,4.0\ncapture,1,2\ncapture,1,2",
+        "client,available,held,total,locked\n1,6.0000,0.0000,6.0000,false\n"
+    );
+
+    tst!(
+        test_authorize_void,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nauthorize,1,2,4.0\nvoid,1,2\ncapture,1,2",
+        "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n"
+    );
+
+    tst!(
+        test_authorize_frozen,
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5.0\ndispute,1,1\nchargeback,1,1\nauthorize,1,3,4.0",
+        "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,true\n"
+    );
+
+    tst!(
+        test_dispute_other_clients_transaction,
+        "type,client,tx,amount\ndeposit,1,1,5.0\ndispute,2,1\nresolve,2,1\nchargeback,2,1",
+        "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n"
+    );
+
+    tst!(
+        test_exact_amounts,
+        "type,client,tx,amount\ndeposit,1,1,0.3\nwithdrawal,1,2,0.1\nwithdrawal,1,3,0.2",
+        "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n"
+    );
+
+    #[test]
+    fn test_amount_parse_and_format() {
+        assert_eq!(amount("0.1") + amount("0.2"), amount("0.3"));
+        assert_eq!(amount("1.77").raw(), 17_700);
+        assert_eq!(amount(".5"), amount("0.5000"));
+        assert_eq!(amount("2.50000"), amount("2.5"));
+        assert_eq!(amount("-0.5").to_string(), "-0.5000");
+        assert_eq!(amount("100.1").to_string(), "100.1000");
+        for bad in ["", ".", "abc", "1e3", "1.23456", "NaN", "inf", "1.2.3"] {
+            assert!(bad.parse::<Amount>().is_err(), "{:?} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn test_client_mismatch() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndispute,2,1\nresolve,2,1\nchargeback,2,1\ndispute,5,99",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let mut engine = mem_engine();
+        let mismatch = TransactionOutcome::rejected(RejectReason::ClientMismatch);
+        assert_eq!(
+            engine.execute_batch(&txns)?,
+            vec![
+                TransactionOutcome::Applied,
+                mismatch,
+                mismatch,
+                mismatch,
+                TransactionOutcome::rejected(RejectReason::UnknownTransaction),
+            ]
+        );
+        assert_eq!(engine.client_ids()?, vec![1]);
+        let acc = engine.account(1)?.unwrap();
+        assert_eq!(acc.available(), amount("5.0"));
+        assert_eq!(acc.held(), Amount::ZERO);
+        assert_eq!(
+            engine.get_transaction(1)?.unwrap().state(),
+            TxnState::Normal
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_amount_overflow() -> Fallible<()> {
+        let max = Amount::from_raw(i64::MAX);
+        assert_eq!(max.checked_add(amount("0.0001")), None);
+        assert_eq!(
+            Amount::from_raw(i64::MIN).checked_sub(amount("0.0001")),
+            None
+        );
+        assert_eq!(
+            amount("1.5").checked_sub(amount("2.0")),
+            Some(amount("-0.5"))
+        );
+
+        let mut acc = Account::new(1);
+        acc.deposit(max)?;
+        assert!(matches!(
+            acc.deposit(amount("0.0001")),
+            Err(MalipoError::AmountOverflow)
+        ));
+        assert_eq!(acc.total(), max);
+
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,900000000000000\ndeposit,1,2,900000000000000\ndeposit,2,3,900000000000000\ndeposit,1,4,1.0",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let mut engine = mem_engine();
+        let overflow = TransactionOutcome::rejected(RejectReason::AmountOverflow);
+        assert_eq!(
+            engine.execute_batch(&txns)?,
+            vec![
+                TransactionOutcome::Applied,
+                overflow,
+                // The deposited total across clients would overflow too
+                overflow,
+                TransactionOutcome::Applied,
+            ]
+        );
+        assert_eq!(
+            engine.account(1)?.unwrap().total(),
+            amount("900000000000001")
+        );
+        assert_eq!(engine.account(2)?, None);
+        assert_eq!(engine.get_transaction(2)?, None);
+        assert_eq!(engine.conservation_delta()?, Amount::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_policy() -> Fallible<()> {
+        let config = EngineConfig {
+            error_policy: HashMap::from([
+                (RejectReason::DuplicateTransaction, ErrorPolicy::Skip),
+                (RejectReason::AmountOverflow, ErrorPolicy::Abort),
+            ]),
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,900000000000000\ndeposit,1,1,5.0\ndeposit,1,2,1.0\ndeposit,1,3,900000000000000\ndeposit,1,4,1.0",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(
+            engine.execute_batch(&txns[..3])?,
+            vec![
+                TransactionOutcome::Applied,
+                TransactionOutcome::rejected(RejectReason::DuplicateTransaction),
+                TransactionOutcome::Applied,
+            ]
+        );
+        assert!(matches!(
+            engine.execute_transaction(txns[3]),
+            Err(MalipoError::TransactionRejected {
+                tx: 3,
+                reason: RejectReason::AmountOverflow
+            })
+        ));
+        assert_eq!(engine.get_transaction(3)?, None);
+        assert_eq!(
+            engine.account(1)?.unwrap().total(),
+            amount("900000000000001")
+        );
+        assert_eq!(
+            engine.execute_transaction(txns[4])?,
+            TransactionOutcome::Applied
+        );
+
+        // An aborted transaction for a new client leaves no account behind
+        let config = EngineConfig {
+            error_policy: HashMap::from([(RejectReason::UnknownTransaction, ErrorPolicy::Abort)]),
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let dispute = Transaction::new(TransactionType::Dispute, 2, 9, None);
+        assert!(engine.execute_transaction(dispute).is_err());
+        assert_eq!(engine.account(2)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_state_transitions() -> Fallible<()> {
+        let mut txn = Transaction::new(TransactionType::Deposit, 1, 1, Some(amount("5.0")));
+        assert_eq!(txn.state(), TxnState::Normal);
+        txn.dispute()?;
+        assert_eq!(txn.state(), TxnState::Disputed);
+        txn.resolve()?;
+        assert_eq!(txn.state(), TxnState::Resolved);
+        txn.dispute()?;
+        txn.chargeback()?;
+        assert_eq!(txn.state(), TxnState::ChargedBack);
+
+        let mut auth = Transaction::new(TransactionType::Authorize, 1, 2, Some(amount("5.0")));
+        auth.authorize()?;
+        auth.capture()?;
+        assert_eq!(auth.state(), TxnState::Captured);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_transaction_state_transitions() {
+        let mut txn = Transaction::new(TransactionType::Deposit, 1, 1, Some(amount("5.0")));
+        assert!(matches!(
+            txn.chargeback(),
+            Err(MalipoError::InvalidStateTransition {
+                tx: 1,
+                from: TxnState::Normal,
+                to: TxnState::ChargedBack
+            })
+        ));
+        assert!(txn.resolve().is_err());
+        assert!(txn.void().is_err());
+        txn.dispute().unwrap();
+        assert!(txn.dispute().is_err());
+        txn.chargeback().unwrap();
+        assert!(txn.chargeback().is_err());
+        assert!(txn.dispute().is_err());
+        assert_eq!(txn.state(), TxnState::ChargedBack);
+    }
+
+    #[test]
+    fn test_invalid_amount() -> Fallible<()> {
+        let mut txns = reader("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,abc")?;
+        assert!(txns.next().unwrap().is_ok());
+        match txns.next().unwrap() {
+            Err(err @ MalipoError::InvalidAmount { line: 3, .. }) => {
+                assert!(err.to_string().contains("abc"));
+            }
+            other => panic!("expected invalid amount error, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_and_negative_amounts() -> Fallible<()> {
+        let input =
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2\ndeposit,1,3,-2.0\ndispute,1,1";
+        let lines: Vec<_> = reader(input)?
+            .filter_map(|txn| match txn {
+                Err(MalipoError::InvalidAmount { line, .. }) => Some(line),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lines, vec![3, 4]);
+
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(input.as_bytes())?;
+        let output_file = NamedTempFile::new()?;
+        let report = PaymentsEngine::process_file(
+            input_file.path().to_str().unwrap(),
+            output_file.path().to_str(),
+            &CsvWriterStdout,
+        )?;
+        let skipped: Vec<_> = report
+            .skipped
+            .iter()
+            .filter_map(|e| match e {
+                MalipoError::InvalidAmount { line, .. } => Some(*line),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(skipped, vec![3, 4]);
+        assert_eq!(
+            std::fs::read_to_string(output_file.path())?,
+            "client,available,held,total,locked\n1,0.0000,5.0000,5.0000,false\n"
+        );
+
+        let (accounts, skipped) = PaymentsEngine::execute_parallel(reader(input)?, 2)?;
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(accounts.iter()?.count(), 1);
+
+        let mut engine = mem_engine();
+        for amount in [None, Some(Amount::ZERO), Some(amount("-1.0"))] {
+            let deposit = Transaction::new(TransactionType::Deposit, 1, 1, amount);
+            assert_eq!(
+                engine.execute_transaction(deposit)?,
+                TransactionOutcome::rejected(RejectReason::InvalidAmount)
+            );
+        }
+        assert_eq!(engine.account(1)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_validate() {
+        use TransactionType::*;
+        let cases = [
+            (Deposit, Some("1.0"), true),
+            (Withdrawal, Some("0.0001"), true),
+            (Authorize, Some("2.5"), true),
+            (Dispute, None, true),
+            (Resolve, None, true),
+            (Chargeback, None, true),
+            (Capture, None, true),
+            (Void, None, true),
+            (Deposit, None, false),
+            (Deposit, Some("0.0"), false),
+            (Withdrawal, Some("0.0"), false),
+            (Withdrawal, Some("-1.0"), false),
+            (Authorize, None, false),
+            (Dispute, Some("1.0"), true),
+            (Chargeback, Some("1.0"), true),
+        ];
+        for (type_, value, valid) in cases {
+            let txn = Transaction::new(type_, 1, 7, value.map(amount));
+            match txn.validate() {
+                Ok(()) => assert!(valid, "{:?} {:?} should be invalid", type_, value),
+                Err(MalipoError::InvalidTransaction { tx: 7, .. }) => {
+                    assert!(!valid, "{:?} {:?} should be valid", type_, value)
+                }
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reference_row_amounts() -> Fallible<()> {
+        let input =
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1,5.0\nresolve,1,1,abc\ndispute,1,1,";
+        let txns = reader(input)?.collect::<Fallible<Vec<_>>>()?;
+        assert!(txns[1..].iter().all(|txn| txn.amount.is_none()));
+
+        let mut engine = mem_engine();
+        engine.execute_batch(&txns[..2])?;
+        assert_eq!(engine.account(1)?.unwrap().held(), amount("5.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scientific_amount() -> Fallible<()> {
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(b"type,client,tx,amount\ndeposit,1,1,1e3\ndeposit,1,2,2.5E-2")?;
+        let path = input_file.path().to_str().unwrap();
+
+        let mut txns = CsvDataReader::new(path)?;
+        assert!(matches!(
+            txns.next().unwrap(),
+            Err(MalipoError::InvalidAmount { line: 2, .. })
+        ));
+
+        let config = CsvReaderConfig {
+            allow_scientific: true,
+            ..Default::default()
+        };
+        let amounts = CsvDataReader::with_config(path, config)?
+            .map(|txn| Ok(txn?.amount.unwrap()))
+            .collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(amounts, vec![amount("1000"), amount("0.025")]);
+
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(
+            b"type,client,tx,amount\ndeposit,1,1,e3\ndeposit,1,2,.e2\ndeposit,1,3,-E1\ndeposit,1,4,+e0\ndeposit,1,5,1e-2147483648",
+        )?;
+        let config = CsvReaderConfig {
+            allow_scientific: true,
+            ..Default::default()
+        };
+        let txns: Vec<_> =
+            CsvDataReader::with_config(input_file.path().to_str().unwrap(), config)?.collect();
+        assert_eq!(txns.len(), 5);
+        for txn in txns {
+            assert!(matches!(txn, Err(MalipoError::InvalidAmount { .. })));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_columns() -> Fallible<()> {
+        let config = CsvReaderConfig {
+            strict_columns: true,
+            ..Default::default()
+        };
+        let input: &[u8] = b"type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1\nresolve,1,1,\ndeposit,1,2,1.0,x\ndeposit,1,3";
+        let mut rdr = CsvDataReader::from_reader_with_config(input, config);
+        let mut engine = mem_engine();
+        for _ in 0..3 {
+            let outcome = engine.execute_transaction(rdr.next().unwrap()?)?;
+            assert_eq!(outcome, TransactionOutcome::Applied);
+        }
+        assert!(matches!(
+            rdr.next().unwrap(),
+            Err(MalipoError::ColumnCount {
+                line: 5,
+                expected: 4,
+                found: 5
+            })
+        ));
+        assert!(matches!(
+            rdr.next().unwrap(),
+            Err(MalipoError::InvalidAmount { line: 6, .. })
+        ));
+
+        // Both trailing optional columns may be left out
+        let config = CsvReaderConfig {
+            strict_columns: true,
+            ..Default::default()
+        };
+        let input: &[u8] = b"type,client,tx,amount,idempotency_key\ndeposit,1,1,5.0,a1\ndeposit,1,2,1.0\ndispute,1,1\ndeposit,1,3,1.0,a2,x";
+        let mut rdr = CsvDataReader::from_reader_with_config(input, config);
+        let txns = rdr.by_ref().take(3).collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(txns[0].idempotency_key, "a1".parse().ok());
+        assert_eq!(txns[1].idempotency_key, None);
+        assert_eq!(txns[2].type_, TransactionType::Dispute);
+        assert!(matches!(
+            rdr.next().unwrap(),
+            Err(MalipoError::ColumnCount {
+                line: 5,
+                expected: 5,
+                found: 6
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_only_input() -> Fallible<()> {
+        let input = "type,client,tx,amount\n";
+        assert_eq!(reader(input)?.count(), 0);
+        let input: &[u8] = input.as_bytes();
+        assert_eq!(CsvDataReader::from_reader(input).count(), 0);
+
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(b"type,client,tx,amount\n")?;
+        let output = NamedTempFile::new()?;
+        PaymentsEngine::process_file(
+            input_file.path().to_str().unwrap(),
+            output.path().to_str(),
+            &CsvWriterStdout,
+        )?;
+        assert_eq!(
+            std::fs::read_to_string(output.path())?,
+            "client,available,held,total,locked\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_amount_parser() -> Fallible<()> {
+        /// Parser reading `free` as zero and dropping a leading `$`
+        struct PriceParser;
+        impl AmountParser for PriceParser {
+            fn parse(&self, value: &str) -> Result<Amount, ParseAmountError> {
+                match value {
+                    "free" => Ok(Amount::ZERO),
+                    value => DefaultAmountParser.parse(value.strip_prefix('$').unwrap_or(value)),
+                }
+            }
+        }
+
+        assert_eq!(PriceParser.parse("free").ok(), Some(Amount::ZERO));
+        assert!(DefaultAmountParser.parse("1e3").is_err());
+        let input: &[u8] =
+            b"type,client,tx,amount\ndeposit,1,1,$5.0\ndeposit,1,2,free\ndeposit,1,3,lots";
+        let mut rdr = CsvDataReader::from_reader(input).with_amount_parser(PriceParser);
+        assert_eq!(rdr.next().unwrap()?.amount, Some(amount("5.0")));
+        // A deposit of zero is left for the engine to accept or reject
+        assert_eq!(rdr.next().unwrap()?.amount, Some(Amount::ZERO));
+        assert!(matches!(
+            rdr.next().unwrap(),
+            Err(MalipoError::InvalidAmount { line: 4, .. })
+        ));
+
+        let input: &[u8] = b"type,client,tx,amount\ndeposit,1,1,$5.0";
+        assert!(CsvDataReader::from_reader(input).next().unwrap().is_err());
+
+        // Scientific notation is expanded before the custom parser sees it
+        let config = CsvReaderConfig {
+            allow_scientific: true,
+            ..Default::default()
+        };
+        let input: &[u8] = b"type,client,tx,amount\ndeposit,1,1,2.5e1";
+        let mut rdr =
+            CsvDataReader::from_reader_with_config(input, config).with_amount_parser(PriceParser);
+        assert_eq!(rdr.next().unwrap()?.amount, Some(amount("25")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_infer_type_from_sign() -> Fallible<()> {
+        let config = || CsvReaderConfig {
+            infer_type_from_sign: true,
+            ..Default::default()
+        };
+        let input: &[u8] = b"client,tx,amount\n1,1,5.0\n1,2,-2.0\n2,3,+1.5";
+        let txns = CsvDataReader::from_reader_with_config(input, config())
+            .map(|txn| txn.map(|txn| (txn.type_, txn.amount.unwrap())))
+            .collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(
+            txns,
+            vec![
+                (TransactionType::Deposit, amount("5.0")),
+                (TransactionType::Withdrawal, amount("2.0")),
+                (TransactionType::Deposit, amount("1.5")),
+            ]
+        );
+
+        let input: &[u8] = b"type,client,tx,amount\ndeposit,1,1,5.0\n,1,2,-2.0\ndispute,1,1";
+        let types = CsvDataReader::from_reader_with_config(input, config())
+            .map(|txn| txn.map(|txn| txn.type_))
+            .collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(
+            types,
+            vec![
+                TransactionType::Deposit,
+                TransactionType::Withdrawal,
+                TransactionType::Dispute
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_insensitive_types() -> Fallible<()> {
+        let config = CsvReaderConfig {
+            case_insensitive_types: true,
+            ..Default::default()
+        };
+        let input: &[u8] =
+            b"type,client,tx,amount\nDeposit,1,1,5.0\nWITHDRAWAL,1,2,2.0\nDiSpUtE,1,1,";
+        let mut engine = mem_engine();
+        for txn in CsvDataReader::from_reader_with_config(input, config) {
+            engine.execute_transaction(txn?)?;
+        }
+        let acc = engine.account(1)?.unwrap();
+        assert_eq!(acc.available(), amount("-2.0"));
+        assert_eq!(acc.held(), amount("5.0"));
+
+        let input: &[u8] = b"type,client,tx,amount\nDeposit,1,1,5.0";
+        let mut rdr = CsvDataReader::from_reader(input);
+        assert!(rdr.next().unwrap().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_records() -> Fallible<()> {
+        let input: &[u8] = b"type,client,tx,amount\ndeposit,1,1, 5.0\ndeposit,1,2,five";
+        let rows: Vec<_> = CsvDataReader::from_reader(input).with_raw().collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(&rows[0].0, vec!["deposit", "1", "1", "5.0"]);
+        assert_eq!(rows[0].1.as_ref().unwrap().amount, Some(amount("5.0")));
+        assert_eq!(&rows[1].0, vec!["deposit", "1", "2", "five"]);
+        assert!(matches!(
+            rows[1].1,
+            Err(MalipoError::InvalidAmount { line: 3, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_id_out_of_range() -> Fallible<()> {
+        let mut txns = reader("type,client,tx,amount\ndeposit,70000,1,10")?;
+        match txns.next().unwrap() {
+            Err(err @ MalipoError::ClientIdOutOfRange { max: 65535, .. }) => {
+                assert!(err.to_string().contains("70000"));
+            }
+            other => panic!("expected client id out of range error, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_withdrawal_dispute() -> Fallible<()> {
+        let head = "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,4.0\ndispute,1,2";
+        let cases = [
+            ("", "6.0", "4.0", "10.0", false),
+            ("\nresolve,1,2", "6.0", "0.0", "6.0", false),
+            ("\nchargeback,1,2", "10.0", "0.0", "10.0", true),
+        ];
+        for (tail, available, held, total, locked) in cases {
+            let mut engine = mem_engine();
+            for txn in reader(&format!("{}{}", head, tail))? {
+                engine.execute_transaction(txn?)?;
+            }
+            let acc = engine.accounts()?.next().unwrap()?;
+            assert_eq!(acc.available(), amount(available));
+            assert_eq!(acc.held(), amount(held));
+            assert_eq!(acc.total(), amount(total));
+            assert_eq!(acc.is_frozen(), locked);
+            assert_eq!(engine.conservation_delta()?, Amount::ZERO);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_clients() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\ndeposit,2,3,1.0\ndispute,2,3\nchargeback,2,3",
+        )?;
+        let mut engine = mem_engine();
+        for txn in txns {
+            engine.execute_transaction(txn?)?;
+        }
+        engine.merge_clients(1, 2)?;
+        assert!(matches!(
+            engine.merge_clients(1, 2),
+            Err(MalipoError::AccountNotFound(2))
+        ));
+
+        let accounts: Vec<_> = engine.accounts()?.collect::<Fallible<_>>()?;
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].client_id, 1);
+        assert_eq!(accounts[0].available(), amount("15.0"));
+        assert_eq!(accounts[0].total(), amount("15.0"));
+        assert!(accounts[0].is_frozen());
+
+        let dispute = Transaction::new(TransactionType::Dispute, 1, 2, None);
+        assert_eq!(
+            engine.execute_transaction(dispute)?,
+            TransactionOutcome::Applied
+        );
+        assert_eq!(engine.accounts()?.next().unwrap()?.held(), amount("5.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejected_withdrawal() -> Fallible<()> {
+        let txns =
+            reader("type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,5.0\ndispute,1,2")?;
+        let mut engine = mem_engine();
+        for txn in txns {
+            engine.execute_transaction(txn?)?;
+        }
+        assert_eq!(
+            engine.rejected(),
+            &[
+                RejectedTransaction {
+                    type_: TransactionType::Withdrawal,
+                    client_id: 1,
+                    id: 2,
+                    reason: RejectReason::InsufficientFunds,
+                },
+                RejectedTransaction {
+                    type_: TransactionType::Dispute,
+                    client_id: 1,
+                    id: 2,
+                    reason: RejectReason::UnknownTransaction,
+                },
+            ]
+        );
+        let acc = engine.accounts()?.next().unwrap()?;
+        assert_eq!(acc.available(), amount("1.0"));
+        assert_eq!(acc.held(), Amount::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_rejected() -> Fallible<()> {
+        let mut input = String::from("type,client,tx,amount\n");
+        for id in 1..=100 {
+            input.push_str(&format!("deposit,1,{},1.0\n", id));
+        }
+        input.push_str("withdrawal,1,101,5.0\nwithdrawal,1,102,5.0");
+        let txns = reader(&input)?.collect::<Fallible<Vec<_>>>()?;
+        let config = EngineConfig {
+            sample_rate: Some(0.0),
+            max_rejected: Some(3),
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        engine.execute_batch(&txns)?;
+        assert_eq!(engine.rejected().len(), 3);
+        assert!(engine
+            .rejected()
+            .iter()
+            .all(|rej| rej.id <= 3 && rej.reason == RejectReason::NotSampled));
+        let counts = engine.rejection_counts();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(
+            counts[&(TransactionType::Deposit, RejectReason::NotSampled)],
+            100
+        );
+        assert_eq!(
+            counts[&(TransactionType::Withdrawal, RejectReason::NotSampled)],
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_transaction() -> Fallible<()> {
+        let mut engine = mem_engine();
+        for txn in reader(
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,4.0\ndispute,1,2\ndeposit,2,3,1.0\ndeposit,2,4,1.0\ndispute,2,4\nchargeback,2,4",
+        )? {
+            engine.execute_transaction(txn?)?;
+        }
+        assert_eq!(engine.reverse_transaction(1)?, TransactionOutcome::Applied);
+        let acc = engine.account(1)?.unwrap();
+        assert_eq!(acc.available(), Amount::ZERO);
+        assert_eq!(acc.total(), amount("4.0"));
+        assert_eq!(engine.conservation_delta()?, Amount::ZERO);
+
+        let dispute = Transaction::new(TransactionType::Dispute, 1, 1, None);
+        assert_eq!(
+            engine.execute_transaction(dispute)?,
+            TransactionOutcome::rejected(RejectReason::NotDisputable)
+        );
+        let rejected = [
+            (1, RejectReason::NotReversible),
+            (2, RejectReason::NotReversible),
+            (3, RejectReason::AccountFrozen),
+            (9, RejectReason::UnknownTransaction),
+        ];
+        for (tx, reason) in rejected {
+            assert_eq!(
+                engine.reverse_transaction(tx)?,
+                TransactionOutcome::rejected(reason)
+            );
+        }
+        assert_eq!(engine.net_positions()?[0], (1, amount("4.0"), Amount::ZERO));
+        // The dispute and the reversals of known transactions are recorded
+        assert_eq!(engine.rejected().len(), 4);
+
+        // A deposit whose funds were withdrawn cannot be taken back
+        let mut engine = mem_engine();
+        for txn in reader("type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,10.0")? {
+            engine.execute_transaction(txn?)?;
+        }
+        assert_eq!(
+            engine.reverse_transaction(1)?,
+            TransactionOutcome::rejected(RejectReason::InsufficientFunds)
+        );
+        let acc = engine.account(1)?.unwrap();
+        assert_eq!((acc.available(), acc.total()), (Amount::ZERO, Amount::ZERO));
+        assert_eq!(
+            engine.get_transaction(1)?.unwrap().state(),
+            TxnState::Normal
+        );
+        assert_eq!(engine.rejected()[0].reason, RejectReason::InsufficientFunds);
+        assert_eq!(engine.reverse_transaction(2)?, TransactionOutcome::Applied);
+        assert_eq!(engine.account(1)?.unwrap().available(), amount("10.0"));
+
+        /// Transactions store that cannot change stored transactions
+        struct FixedTransactions(TransactionsMemStore);
+        impl Store<TransactionId, Transaction> for FixedTransactions {
+            fn create(&mut self, item: Transaction) -> Fallible<()> {
+                self.0.create(item)
+            }
+            fn delete(&mut self, id: TransactionId) -> Fallible<()> {
+                self.0.delete(id)
+            }
+            fn get(&mut self, id: TransactionId) -> Fallible<Transaction> {
+                self.0.get(id)
+            }
+            fn update(&mut self, _item: Transaction) -> Fallible<()> {
+                Err(std::io::Error::other("store unavailable").into())
+            }
+            fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Transaction>> + '_>> {
+                self.0.iter()
+            }
+        }
+        // The account is rolled back when the reversed transaction cannot be
+        // stored
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(FixedTransactions(TransactionsMemStore::new()));
+        let mut engine = PaymentsEngine::new(acc_store, txn_store);
+        for txn in reader("type,client,tx,amount\ndeposit,1,1,10.0")? {
+            engine.execute_transaction(txn?)?;
+        }
+        assert!(matches!(
+            engine.reverse_transaction(1),
+            Err(MalipoError::IoError(_))
+        ));
+        assert_eq!(engine.account(1)?.unwrap().available(), amount("10.0"));
+        assert_eq!(engine.stats().deposited, amount("10.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unwind() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,3.0\nwithdrawal,1,3,4.0\ndispute,1,2\nresolve,1,2\ndispute,1,2\nchargeback,1,2\ndeposit,2,4,8.0\nauthorize,2,5,2.0\ncapture,2,5\nwithdrawal,2,6,1.0\ndispute,2,6\nwithdrawal,2,7,100.0",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let mut engine = mem_engine();
+        let mut applied = vec![];
+        for txn in &txns {
+            if engine.execute_transaction(*txn)? == TransactionOutcome::Applied {
+                applied.push(*txn);
+            }
+        }
+        let mut before = vec![];
+        CsvWriterStdout::write(engine.accounts()?, Some(&mut before))?;
+
+        engine.unwind(&applied)?;
+        for acc in engine.accounts()? {
+            let acc = acc?;
+            assert_eq!(acc.available(), Amount::ZERO);
+            assert_eq!(acc.held(), Amount::ZERO);
+            assert_eq!(acc.total(), Amount::ZERO);
+            assert!(!acc.is_frozen());
+        }
+        assert_eq!(engine.stats().deposited, Amount::ZERO);
+        assert_eq!(engine.stats().withdrawn, Amount::ZERO);
+        assert_eq!(engine.stats().charged_back, Amount::ZERO);
+
+        for txn in txns {
+            engine.execute_transaction(txn)?;
+        }
+        let mut after = vec![];
+        CsvWriterStdout::write(engine.accounts()?, Some(&mut after))?;
+        assert_eq!(String::from_utf8(after)?, String::from_utf8(before)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_parallel() -> Fallible<()> {
+        let mut input = String::from("type,client,tx,amount\n");
+        for id in 1..=2000u32 {
+            let client = id % 37;
+            match id % 5 {
+                0 => input.push_str(&format!("withdrawal,{},{},2.5\n", client, id)),
+                1 if id > 37 => input.push_str(&format!("dispute,{},{}\n", client, id - 37)),
+                2 if id > 148 => input.push_str(&format!("chargeback,{},{}\n", client, id - 148)),
+                _ => input.push_str(&format!("deposit,{},{},1.25\n", client, id)),
+            }
+        }
+
+        let mut engine = mem_engine();
+        for txn in reader(&input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        let mut sequential = vec![];
+        CsvWriterStdout::write(engine.accounts()?, Some(&mut sequential))?;
+
+        for threads in [1, 4] {
+            let (accounts, _) = PaymentsEngine::execute_parallel(reader(&input)?, threads)?;
+            let mut parallel = vec![];
+            CsvWriterStdout::write(accounts.iter()?, Some(&mut parallel))?;
+            assert_eq!(
+                String::from_utf8(parallel)?,
+                String::from_utf8(sequential.clone())?
+            );
+        }
+
+        // Clients 1 and 2 land on different workers but share a transaction ID
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,1,7.0\ndispute,2,1";
+        let mut engine = mem_engine();
+        for txn in reader(input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        let mut sequential = vec![];
+        CsvWriterStdout::write(engine.accounts()?, Some(&mut sequential))?;
+        for threads in [1, 2] {
+            let (accounts, _) = PaymentsEngine::execute_parallel(reader(input)?, threads)?;
+            let mut parallel = vec![];
+            CsvWriterStdout::write(accounts.iter()?, Some(&mut parallel))?;
+            assert_eq!(
+                String::from_utf8(parallel)?,
+                String::from_utf8(sequential.clone())?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_disputable() -> Fallible<()> {
+        let mut engine = mem_engine();
+        for txn in reader(
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,1.0\ndispute,1,2\nauthorize,1,3,1.0\ndeposit,2,4,1.0\ndeposit,2,5,1.0\ndispute,2,5\nchargeback,2,5\ndeposit,1,6,1.0\ndispute,1,6\nresolve,1,6",
+        )? {
+            engine.execute_transaction(txn?)?;
+        }
+        // Read-only queries only need a shared reference
+        let engine = &engine;
+        assert!(engine.is_disputable(1, 1)?);
+        assert!(engine.is_disputable(6, 1)?, "resolved disputes can reopen");
+        assert!(!engine.is_disputable(9, 1)?, "unknown transaction");
+        assert!(!engine.is_disputable(1, 2)?, "another client's transaction");
+        assert!(!engine.is_disputable(3, 1)?, "authorization");
+        assert!(!engine.is_disputable(2, 1)?, "already disputed");
+        assert!(!engine.is_disputable(5, 2)?, "charged back");
+        assert!(!engine.is_disputable(4, 2)?, "frozen account");
+        Ok(())
+    }
+
+    #[test]
+    fn test_accounts_for_transaction() -> Fallible<()> {
+        let mut engine = mem_engine();
+        for txn in reader("type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\ndispute,2,2")? {
+            engine.execute_transaction(txn?)?;
+        }
+        let engine = &engine;
+        assert_eq!(engine.accounts_for_transaction(1)?, vec![1]);
+        assert_eq!(engine.accounts_for_transaction(2)?, vec![2]);
+        assert!(engine.accounts_for_transaction(3)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_writer() -> Fallible<()> {
+        let mut engine = mem_engine();
+        for txn in reader("type,client,tx,amount\ndeposit,2,1,5.0\ndeposit,1,2,1.25\ndispute,1,2")?
+        {
+            engine.execute_transaction(txn?)?;
+        }
+        let mut output = vec![];
+        JsonWriter.write(engine.accounts()?, &mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            concat!(
+                r#"[{"client":1,"available":"0.0000","held":"1.2500","total":"1.2500","locked":false},"#,
+                r#"{"client":2,"available":"5.0000","held":"0.0000","total":"5.0000","locked":false}]"#,
+                "\n"
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_transaction() -> Fallible<()> {
+        let mut engine = mem_engine();
+        for txn in reader("type,client,tx,amount\ndeposit,3,8,2.5\ndispute,3,8")? {
+            engine.execute_transaction(txn?)?;
+        }
+        let txn = engine.get_transaction(8)?.unwrap();
+        assert_eq!(txn.type_, TransactionType::Deposit);
+        assert_eq!(txn.client_id, 3);
+        assert_eq!(txn.amount, Some(amount("2.5")));
+        assert_eq!(txn.state(), TxnState::Disputed);
+        assert_eq!(engine.get_transaction(9)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_ids() -> Fallible<()> {
+        let input = "type,client,tx,amount\ndeposit,7,1,5.0\ndeposit,2,2,1.0\nwithdrawal,30,3,1.0\ndeposit,2,4,1.0";
+        let mut engine = mem_engine();
+        for txn in reader(input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        // Client 30's only transaction is rejected, so it has no account
+        assert_eq!(engine.client_ids()?, vec![2, 7]);
+
+        let acc_store = InstrumentedStore::new(AccountsMemStore::new());
+        let counter = acc_store.counter();
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::new(Box::new(acc_store), txn_store);
+        for txn in reader(input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        assert_eq!(engine.client_ids()?, vec![2, 7]);
+        assert_eq!(counter.counts().ids, 1);
+        assert_eq!(counter.counts().iter, 0);
+
+        // Stores without an ID index fall back to reading every account
+        let acc_store = Box::new(ForgetfulAccounts(AccountsMemStore::new()));
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::new(acc_store, txn_store);
+        for txn in reader(input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        assert_eq!(engine.client_ids()?, vec![2, 7]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_writer() -> Fallible<()> {
+        let mut engine = mem_engine();
+        for txn in
+            reader("type,client,tx,amount\ndeposit,12,1,1500.0\ndeposit,1,2,1.25\ndispute,1,2")?
+        {
+            engine.execute_transaction(txn?)?;
+        }
+        let mut output = vec![];
+        TableWriter.write(engine.accounts()?, &mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            concat!(
+                "client  available    held      total  locked\n",
+                "------  ---------  ------  ---------  ------\n",
+                "     1     0.0000  1.2500     1.2500   false\n",
+                "    12  1500.0000  0.0000  1500.0000   false\n",
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotating_writer() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("accounts.csv");
+        let mut wtr = RotatingWriter::new(path.to_str().unwrap(), Rotation::Rows(2));
+        for client in 1..=5 {
+            let mut acc = Account::new(client);
+            acc.deposit(amount("1.5"))?;
+            wtr.write(&acc)?;
+        }
+        wtr.flush()?;
+        let names: Vec<_> = wtr
+            .files()
+            .iter()
+            .map(|file| file.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["accounts.000.csv", "accounts.001.csv", "accounts.002.csv"]
+        );
+        let header = "client,available,held,total,locked\n";
+        assert_eq!(
+            std::fs::read_to_string(&wtr.files()[1])?,
+            format!(
+                "{}3,1.5000,0.0000,1.5000,false\n4,1.5000,0.0000,1.5000,false\n",
+                header
+            )
+        );
+        assert_eq!(
+            std::fs::read_to_string(&wtr.files()[2])?,
+            format!("{}5,1.5000,0.0000,1.5000,false\n", header)
+        );
+
+        let path = dir.path().join("sized.csv");
+        let mut wtr = RotatingWriter::new(path.to_str().unwrap(), Rotation::Bytes(100));
+        for client in 1..=5 {
+            wtr.write(&Account::new(client))?;
+        }
+        wtr.flush()?;
+        assert_eq!(wtr.files().len(), 2);
+        for file in wtr.files() {
+            assert!(std::fs::read_to_string(file)?.starts_with(header));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_queries() -> Fallible<()> {
+        let mut engine = mem_engine();
+        for txn in reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\nwithdrawal,1,3,1.0\ndispute,1,1",
+        )? {
+            engine.execute_transaction(txn?)?;
+        }
+        let acc = engine.account(1)?.unwrap();
+        assert_eq!(acc.available(), amount("-1.0"));
+        assert_eq!(acc.held(), amount("5.0"));
+        let mut ids: Vec<_> = engine
+            .transactions_for(1)?
+            .iter()
+            .map(|txn| txn.id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 3]);
+
+        assert!(engine.account(9)?.is_none());
+        assert!(engine.transactions_for(9)?.is_empty());
+        assert_eq!(engine.accounts()?.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_filtered() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,15.0\ndeposit,3,3,10.0\ndeposit,3,4,0.5",
+        )?;
+        let mut engine = mem_engine();
+        for txn in txns {
+            engine.execute_transaction(txn?)?;
+        }
+        let mut output = vec![];
+        engine.write_filtered(|acc| acc.total() > amount("10.0"), &mut output)?;
+        let data = String::from_utf8(output)?;
+        assert_eq!(
+            data,
+            "client,available,held,total,locked\n2,15.0000,0.0000,15.0000,false\n3,10.5000,0.0000,10.5000,false\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_conservation_delta() -> Fallible<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\nwithdrawal,1,3,1.5\ndispute,2,2\nchargeback,2,2";
+        let mut engine = mem_engine();
+        for txn in reader(input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        assert_eq!(engine.conservation_delta()?, Amount::ZERO);
+
+        let acc_store = Box::new(LeakyAccounts(AccountsMemStore::new()));
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::new(acc_store, txn_store);
+        for txn in reader(input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        assert_ne!(engine.conservation_delta()?, Amount::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_conservation() -> Fallible<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,1.5\ndispute,1,1";
+        let config = || EngineConfig {
+            verify_conservation: true,
+            ..Default::default()
+        };
+
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config());
+        for txn in reader(input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        engine.finish()?;
+
+        let acc_store = Box::new(LeakyAccounts(AccountsMemStore::new()));
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config());
+        for txn in reader(input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        assert!(matches!(
+            engine.finish(),
+            Err(MalipoError::ConservationViolation { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_applied_sink() -> Fallible<()> {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let applied = Rc::new(RefCell::new(vec![]));
+        let sink = applied.clone();
+        let config = EngineConfig {
+            applied_sink: Some(Box::new(move |txn: &Transaction| {
+                sink.borrow_mut().push(txn.id)
+            })),
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,9.0\ndispute,1,7\nwithdrawal,1,3,1.0",
+        )?;
+        for txn in txns {
+            engine.execute_transaction(txn?)?;
+        }
+        assert_eq!(*applied.borrow(), vec![1, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_log() -> Fallible<()> {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// Audit log keeping the events in a shared list
+        struct EventLog(Rc<RefCell<Vec<AuditEvent>>>);
+        impl AuditLog for EventLog {
+            fn on_event(&mut self, event: &AuditEvent) -> Fallible<()> {
+                self.0.borrow_mut().push(*event);
+                Ok(())
+            }
+        }
+
+        let events = Rc::new(RefCell::new(vec![]));
+        let config = EngineConfig {
+            audit_log: Some(Box::new(EventLog(events.clone()))),
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,9.0\ndispute,1,9\ndispute,2,1\ndeposit,1,1,5.0\nresolve,1,1\ndispute,1,1\nchargeback,1,1\ndeposit,1,3,1.0",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        for txn in &txns {
+            engine.execute_transaction(*txn)?;
+        }
+
+        let events = events.borrow();
+        let mut deposited = Account::new(1);
+        deposited.deposit(amount("5.0"))?;
+        assert_eq!(
+            events[0],
+            AuditEvent::Applied {
+                txn: txns[0],
+                before: Account::new(1),
+                after: deposited,
+            }
+        );
+        assert_eq!(
+            events[1..6],
+            [
+                AuditEvent::SkippedInsufficientFunds { txn: txns[1] },
+                AuditEvent::SkippedUnknownTxn { txn: txns[2] },
+                AuditEvent::SkippedClientMismatch { txn: txns[3] },
+                AuditEvent::SkippedDuplicate { txn: txns[4] },
+                AuditEvent::Skipped {
+                    txn: txns[5],
+                    reason: RejectReason::NotDisputed
+                },
+            ]
+        );
+        assert!(matches!(events[6], AuditEvent::Applied { .. }));
+        assert!(matches!(events[7], AuditEvent::Applied { after, .. } if after.is_frozen()));
+        assert_eq!(events[8], AuditEvent::SkippedFrozen { txn: txns[8] });
+
+        let mut output = vec![];
+        JsonLinesAuditLog::new(&mut output).on_event(&events[2])?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "{\"event\":\"skipped_unknown_txn\",\"txn\":{\"type\":\"dispute\",\"client\":1,\"tx\":9,\"amount\":null}}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_event_log() -> Fallible<()> {
+        let log_file = NamedTempFile::new()?;
+        let config = EngineConfig {
+            audit_log: Some(Box::new(JsonLinesEventLog::new(log_file.reopen()?))),
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,9.0\ndispute,1,1\nresolve,1,7",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        for txn in txns {
+            engine.execute_transaction(txn)?;
+        }
+        drop(engine);
+
+        let log = std::fs::read_to_string(log_file.path())?;
+        assert_eq!(
+            log.lines().collect::<Vec<_>>(),
+            vec![
+                r#"{"tx":1,"client":1,"type":"deposit","outcome":"applied","available":"5.0000","held":"0.0000","total":"5.0000","locked":false}"#,
+                r#"{"tx":2,"client":1,"type":"withdrawal","outcome":"skipped","reason":"insufficient_funds"}"#,
+                r#"{"tx":1,"client":1,"type":"dispute","outcome":"applied","available":"0.0000","held":"5.0000","total":"5.0000","locked":false}"#,
+                r#"{"tx":7,"client":1,"type":"resolve","outcome":"skipped","reason":"unknown_transaction"}"#,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_over_resolve() -> Fallible<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1\nresolve,1,1";
+        let acc_store = Box::new(ForgetfulAccounts(AccountsMemStore::new()));
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::new(acc_store, txn_store);
+        for txn in reader(input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        let acc = engine.accounts()?.next().unwrap()?;
+        assert_eq!(acc.held(), Amount::ZERO);
+        assert_eq!(acc.available(), amount("5.0"));
+
+        let config = EngineConfig {
+            error_on_over_resolve: true,
+            ..Default::default()
+        };
+        let acc_store = Box::new(ForgetfulAccounts(AccountsMemStore::new()));
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let results: Vec<_> = reader(input)?
+            .map(|txn| engine.execute_transaction(txn?))
+            .collect();
+        assert!(matches!(
+            results[2],
+            Err(MalipoError::InvariantViolation(_))
+        ));
+        assert!(engine
+            .accounts()?
+            .all(|acc| acc.is_ok_and(|acc| acc.held() >= Amount::ZERO)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_noop_transactions() -> Fallible<()> {
+        let config = EngineConfig {
+            allow_zero_deposit_activation: true,
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,0.0\ndeposit,1,2,5.0\ndispute,1,2\nresolve,1,2\ndispute,1,1\nresolve,1,1",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let outcomes = engine.execute_batch(&txns)?;
+        assert!(outcomes.iter().all(|o| *o == TransactionOutcome::Applied));
+        // The zero deposit, and the dispute and resolve of it
+        assert_eq!(engine.noop_transactions(), &[1, 1, 1]);
+
+        let acc_store = Box::new(ForgetfulAccounts(AccountsMemStore::new()));
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::new(acc_store, txn_store);
+        for txn in reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,1.0\ndispute,1,1\nresolve,1,1",
+        )? {
+            engine.execute_transaction(txn?)?;
+        }
+        // The store drops the held funds, so the resolve has nothing to release
+        assert_eq!(engine.noop_transactions(), &[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_zero_deposit_activation() -> Fallible<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1,0\ndeposit,2,2,0.0000";
+        let zero_withdrawal =
+            Transaction::new(TransactionType::Withdrawal, 1, 3, Some(Amount::ZERO));
+        assert!(matches!(
+            reader("type,client,tx,amount\nwithdrawal,1,3,0")?
+                .next()
+                .unwrap(),
+            Err(MalipoError::InvalidAmount { line: 2, .. })
+        ));
+
+        // Rejected by default, without creating an account
+        let mut engine = mem_engine();
+        for txn in reader(input)? {
+            assert_eq!(
+                engine.execute_transaction(txn?)?,
+                TransactionOutcome::rejected(RejectReason::InvalidAmount)
+            );
+        }
+        assert!(engine.client_ids()?.is_empty());
+
+        let config = EngineConfig {
+            allow_zero_deposit_activation: true,
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        for txn in reader(input)? {
+            assert_eq!(
+                engine.execute_transaction(txn?)?,
+                TransactionOutcome::Applied
+            );
+        }
+        assert_eq!(engine.client_ids()?, vec![1, 2]);
+        assert_eq!(engine.account(1)?, Some(Account::new(1)));
+        // The activation is stored, so its ID cannot be reused
+        assert!(engine.get_transaction(1)?.is_some());
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 1, Some(amount("5.0")));
+        assert_eq!(
+            engine.execute_transaction(deposit)?,
+            TransactionOutcome::rejected(RejectReason::DuplicateTransaction)
+        );
+        // Zero withdrawals are still rejected
+        assert_eq!(
+            engine.execute_transaction(zero_withdrawal)?,
+            TransactionOutcome::rejected(RejectReason::InvalidAmount)
+        );
+        assert_eq!(engine.get_transaction(3)?, None);
+        assert_eq!(engine.account(1)?, Some(Account::new(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_ordering() -> Fallible<()> {
+        let txns = reader("type,client,tx,amount\ndispute,1,1\ndeposit,1,1,5.0")?
+            .collect::<Fallible<Vec<_>>>()?;
+
+        let outcomes = mem_engine().execute_batch(&txns)?;
+        assert_eq!(
+            outcomes,
+            vec![
+                TransactionOutcome::rejected(RejectReason::UnknownTransaction),
+                TransactionOutcome::Applied
+            ]
+        );
+
+        let config = EngineConfig {
+            strict_ordering: true,
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        assert!(matches!(
+            engine.execute_batch(&txns),
+            Err(MalipoError::DisputeBeforeDeposit { tx: 1 })
+        ));
+        assert_eq!(engine.accounts()?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_idempotency_key() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount,idempotency_key\ndeposit,1,1,5.0,pay_abc\ndeposit,1,2,5.0,pay_abc\ndeposit,1,3,2.0,\ndeposit,1,4,1.0",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(txns[0].idempotency_key, "pay_abc".parse().ok());
+        assert_eq!(txns[0].idempotency_key.unwrap().as_str(), "pay_abc");
+        assert_eq!(txns[2].idempotency_key, None);
+        // Keys are stored out of line, so they barely grow a transaction
+        assert_eq!(std::mem::size_of::<Option<IdempotencyKey>>(), 4);
+
+        let mut engine = mem_engine();
+        let outcomes = engine.execute_batch(&txns)?;
+        assert_eq!(
+            outcomes,
+            vec![
+                TransactionOutcome::Applied,
+                TransactionOutcome::rejected(RejectReason::DuplicateIdempotencyKey),
+                TransactionOutcome::Applied,
+                TransactionOutcome::Applied,
+            ]
+        );
+        assert_eq!(engine.account(1)?.unwrap().available(), amount("8.0"));
+
+        // An overlong key skips its row rather than failing the run
+        let long_key = "k".repeat(IdempotencyKey::MAX_LEN + 1);
+        let input = format!(
+            "type,client,tx,amount,idempotency_key\ndeposit,1,1,5.0,{}\ndeposit,1,2,1.0,ok",
+            long_key
+        );
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(input.as_bytes())?;
+        let output_file = NamedTempFile::new()?;
+        let report = PaymentsEngine::process_file(
+            input_file.path().to_str().unwrap(),
+            output_file.path().to_str(),
+            &CsvWriterStdout,
+        )?;
+        assert!(matches!(
+            report.skipped[..],
+            [MalipoError::InvalidIdempotencyKey { line: 2, .. }]
+        ));
+        assert_eq!(report.stats.deposited, amount("1.0"));
+
+        // Keys survive spilling to disk
+        let segment = NamedTempFile::new()?;
+        let mut store = SpillingTransactionsStore::new(segment.path().to_str().unwrap(), 0)?;
+        store.create(txns[0])?;
+        assert_eq!(store.spilled(), 1);
+        assert_eq!(store.get(1)?.idempotency_key, txns[0].idempotency_key);
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_activity() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\nwithdrawal,1,3,1.0\ndeposit,3,4,1.0\ndispute,2,2\nwithdrawal,3,5,9.0",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let mut engine = mem_engine();
+        engine.execute_batch(&txns)?;
+        let mut last_activity = engine
+            .last_activity()
+            .iter()
+            .map(|(&client, &index)| (client, index))
+            .collect::<Vec<_>>();
+        last_activity.sort();
+        // Client 3's withdrawal is rejected, so its deposit stays its last activity
+        assert_eq!(last_activity, vec![(1, 2), (2, 4), (3, 3)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deposit_supervised() -> Fallible<()> {
+        let mut engine = mem_engine();
+        for txn in reader("type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1\nchargeback,1,1")? {
+            engine.execute_transaction(txn?)?;
+        }
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 2, Some(amount("3.0")));
+        assert_eq!(
+            engine.execute_transaction(deposit)?,
+            TransactionOutcome::rejected(RejectReason::AccountFrozen)
+        );
+        assert_eq!(
+            engine.deposit_supervised(deposit)?,
+            TransactionOutcome::Applied
+        );
+        let acc = engine.account(1)?.unwrap();
+        assert_eq!(acc.available(), amount("3.0"));
+        assert!(acc.is_frozen());
+
+        let withdrawal = Transaction::new(TransactionType::Withdrawal, 1, 3, Some(amount("1.0")));
+        assert!(matches!(
+            engine.deposit_supervised(withdrawal),
+            Err(MalipoError::InvalidTransaction { tx: 3, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_rate() -> Fallible<()> {
+        let mut input = String::from("type,client,tx,amount\n");
+        for id in 1..=200 {
+            input.push_str(&format!("deposit,{},{},1.0\n", id % 5, id));
+        }
+        for id in 1..=200 {
+            input.push_str(&format!("dispute,{},{}\n", id % 5, id));
+        }
+        let txns = reader(&input)?.collect::<Fallible<Vec<_>>>()?;
+        let sample = |sample_rate, seed| -> Fallible<Vec<TransactionOutcome>> {
+            let config = EngineConfig {
+                sample_rate: Some(sample_rate),
+                seed,
+                ..Default::default()
+            };
+            let acc_store = Box::new(AccountsMemStore::new());
+            let txn_store = Box::new(TransactionsMemStore::new());
+            PaymentsEngine::with_config(acc_store, txn_store, config).execute_batch(&txns)
+        };
+
+        let outcomes = sample(0.25, 7)?;
+        assert_eq!(outcomes, sample(0.25, 7)?);
+        assert_ne!(outcomes, sample(0.25, 8)?);
+        let (deposits, disputes) = outcomes.split_at(200);
+        // Each dispute is kept exactly when its deposit is
+        assert_eq!(deposits, disputes);
+        let applied = deposits
+            .iter()
+            .filter(|outcome| **outcome == TransactionOutcome::Applied)
+            .count();
+        assert!((30..70).contains(&applied), "{} applied", applied);
+        assert!(deposits.iter().all(|outcome| matches!(
+            outcome,
+            TransactionOutcome::Applied
+                | TransactionOutcome::Rejected {
+                    reason: RejectReason::NotSampled
+                }
+        )));
+
+        assert!(sample(1.0, 7)?
+            .iter()
+            .all(|outcome| *outcome == TransactionOutcome::Applied));
+        assert!(sample(0.0, 7)?
+            .iter()
+            .all(|outcome| *outcome == TransactionOutcome::rejected(RejectReason::NotSampled)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_maintenance_hook() -> Fallible<()> {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let runs = Rc::new(RefCell::new(vec![]));
+        let seen = runs.clone();
+        let config = EngineConfig {
+            maintenance_interval: Some(3),
+            maintenance_hook: Some(Box::new(move |engine: &mut PaymentsEngine| {
+                let applied = engine.last_activity().values().max().copied();
+                seen.borrow_mut().push(applied);
+            })),
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let mut input = String::from("type,client,tx,amount\n");
+        for id in 0..10 {
+            input.push_str(&format!("deposit,1,{},1.0\n", id));
+        }
+        // Rejected, so not counted towards the interval
+        input.push_str("withdrawal,1,10,99.0\n");
+        for id in 11..13 {
+            input.push_str(&format!("deposit,1,{},1.0\n", id));
+        }
+        for txn in reader(&input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        // After the 3rd, 6th, 9th and 12th applied transactions, by input index
+        assert_eq!(*runs.borrow(), vec![Some(2), Some(5), Some(8), Some(12)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_net_positions() -> Fallible<()> {
+        let mut engine = mem_engine();
+        for txn in reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,3.0\nwithdrawal,1,3,2.0\ndeposit,2,4,4.0\nwithdrawal,2,5,9.0\ndispute,1,1\nchargeback,1,1",
+        )? {
+            engine.execute_transaction(txn?)?;
+        }
+        assert_eq!(
+            engine.net_positions()?,
+            vec![
+                (1, amount("8.0"), amount("2.0")),
+                (2, amount("4.0"), Amount::ZERO),
+            ]
+        );
+        assert_eq!(engine.account(1)?.unwrap().total(), amount("1.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_only() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,2.0\ndispute,1,1\nresolve,1,1\nchargeback,1,1\nauthorize,1,3,1.0\ndeposit,1,4,1.0",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let config = EngineConfig {
+            append_only: true,
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let outcomes = engine.execute_batch(&txns)?;
+        let rejected = TransactionOutcome::rejected(RejectReason::AppendOnly);
+        assert_eq!(
+            outcomes,
+            vec![
+                TransactionOutcome::Applied,
+                TransactionOutcome::Applied,
+                rejected,
+                rejected,
+                rejected,
+                rejected,
+                TransactionOutcome::Applied,
+            ]
+        );
+        assert_eq!(engine.reverse_transaction(1)?, rejected);
+        engine.execute_transaction(Transaction::new(
+            TransactionType::Deposit,
+            2,
+            5,
+            Some(amount("1.0")),
+        ))?;
+        assert!(matches!(
+            engine.merge_clients(1, 2),
+            Err(MalipoError::AppendOnly)
+        ));
+        assert!(matches!(
+            engine.unwind(&txns[..2]),
+            Err(MalipoError::AppendOnly)
+        ));
+        assert_eq!(engine.client_ids()?, vec![1, 2]);
+        assert!(engine.get_transaction(2)?.is_some());
+        let acc = engine.account(1)?.unwrap();
+        assert_eq!(acc.available(), amount("4.0"));
+        assert_eq!(acc.held(), Amount::ZERO);
+        assert!(!acc.is_frozen());
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_accounts() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\ndeposit,3,3,1.0\ndeposit,1,4,1.0",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let config = EngineConfig {
+            max_accounts: Some(2),
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let outcomes = engine.execute_batch(&txns)?;
+        assert_eq!(
+            outcomes,
+            vec![
+                TransactionOutcome::Applied,
+                TransactionOutcome::Applied,
+                TransactionOutcome::rejected(RejectReason::AccountLimitReached),
+                TransactionOutcome::Applied,
+            ]
+        );
+        assert_eq!(engine.accounts()?.count(), 2);
+        assert_eq!(engine.account(3)?, None);
+        assert_eq!(engine.account(1)?.unwrap().available(), amount("6.0"));
+
+        // Rejected transactions for new clients take no slot, merges free one
+        let config = EngineConfig {
+            max_accounts: Some(2),
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndispute,9,99\nwithdrawal,8,2,1.0\ndeposit,2,3,3.0\ndeposit,3,4,1.0",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let outcomes = engine.execute_batch(&txns)?;
+        assert_eq!(outcomes[3], TransactionOutcome::Applied);
+        assert_eq!(
+            outcomes[4],
+            TransactionOutcome::rejected(RejectReason::AccountLimitReached)
+        );
+        engine.merge_clients(1, 2)?;
+        assert_eq!(
+            engine.execute_transaction(txns[4])?,
+            TransactionOutcome::Applied
+        );
+        assert_eq!(engine.accounts()?.count(), 2);
+
+        // Merging into new clients frees no slot
+        let config = EngineConfig {
+            max_accounts: Some(2),
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        for txn in reader("type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0")? {
+            engine.execute_transaction(txn?)?;
+        }
+        engine.merge_clients(3, 1)?;
+        engine.merge_clients(4, 2)?;
+        for txn in reader("type,client,tx,amount\ndeposit,5,3,1.0\ndeposit,6,4,1.0")? {
+            assert_eq!(
+                engine.execute_transaction(txn?)?,
+                TransactionOutcome::rejected(RejectReason::AccountLimitReached)
+            );
+        }
+        assert_eq!(engine.client_ids()?, vec![3, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_allow_withdrawal_disputes() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,2.0\ndispute,1,2\ndispute,1,1",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let mut engine = mem_engine();
+        let outcomes = engine.execute_batch(&txns)?;
+        assert_eq!(outcomes[2], TransactionOutcome::Applied);
+        assert_eq!(engine.account(1)?.unwrap().held(), amount("7.0"));
+
+        let config = EngineConfig {
+            allow_withdrawal_disputes: false,
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let outcomes = engine.execute_batch(&txns)?;
+        assert_eq!(
+            outcomes[2],
+            TransactionOutcome::rejected(RejectReason::NotDisputable)
+        );
+        assert_eq!(outcomes[3], TransactionOutcome::Applied);
+        assert!(!engine.is_disputable(2, 1)?);
+        assert_eq!(engine.account(1)?.unwrap().held(), amount("5.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_chargeback() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,3.0\ndispute,1,1\nchargeback,1,1\ndispute,1,2\nresolve,1,2\nchargeback,1,2",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let outcomes = mem_engine().execute_batch(&txns)?;
+        assert_eq!(outcomes[3], TransactionOutcome::Applied);
+        assert_eq!(
+            outcomes[6],
+            TransactionOutcome::rejected(RejectReason::NotDisputed)
+        );
+
+        let config = EngineConfig {
+            strict_chargeback: true,
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let outcomes = engine.execute_batch(&txns)?;
+        assert_eq!(outcomes[3], TransactionOutcome::Applied);
+        assert_eq!(
+            outcomes[6],
+            TransactionOutcome::rejected(RejectReason::ChargebackAfterResolve)
+        );
+        let acc = engine.account(1)?.unwrap();
+        assert_eq!(acc.total(), amount("3.0"));
+        assert_eq!(acc.held(), Amount::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bound_dispute_holds() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,1.0\nwithdrawal,1,3,4.0\ndispute,1,2\ndispute,1,1",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let outcomes = mem_engine().execute_batch(&txns)?;
+        assert_eq!(outcomes[4], TransactionOutcome::Applied);
+
+        let config = EngineConfig {
+            bound_dispute_holds: true,
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        for txn in &txns[..4] {
+            assert_eq!(
+                engine.execute_transaction(*txn)?,
+                TransactionOutcome::Applied
+            );
+        }
+        assert!(matches!(
+            engine.execute_transaction(txns[4]),
+            Err(MalipoError::DisputeExceedsAccount { tx: 1, .. })
+        ));
+        let acc = engine.account(1)?.unwrap();
+        assert_eq!(acc.held(), amount("1.0"));
+        assert_eq!(acc.available(), amount("1.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_resolve() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1\nresolve,1,1\nresolve,1,1",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+
+        let outcomes = mem_engine().execute_batch(&txns)?;
+        assert_eq!(
+            outcomes[3],
+            TransactionOutcome::rejected(RejectReason::NotDisputed)
+        );
+
+        let config = EngineConfig {
+            strict_resolve: true,
+            ..Default::default()
+        };
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(TransactionsMemStore::new());
+        let mut engine = PaymentsEngine::with_config(acc_store, txn_store, config);
+        let outcomes = engine.execute_batch(&txns)?;
+        assert_eq!(outcomes[2], TransactionOutcome::Applied);
+        assert_eq!(
+            outcomes[3],
+            TransactionOutcome::rejected(RejectReason::AlreadyResolved)
+        );
+        assert_eq!(
+            engine.accounts()?.next().unwrap()?.available(),
+            amount("5.0")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_by_id() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,1,5.0\ndeposit,1,2,1.0\ndispute,1,1\ndispute,1,1",
+        )?
+        .dedup_by_id();
+        let mut engine = mem_engine();
+        let mut applied = vec![];
+        for txn in txns {
+            let txn = txn?;
+            engine.execute_transaction(txn)?;
+            applied.push((txn.type_, txn.id));
+        }
+        assert_eq!(
+            applied,
+            vec![
+                (TransactionType::Deposit, 1),
+                (TransactionType::Deposit, 2),
+                (TransactionType::Dispute, 1)
+            ]
+        );
+        let acc = engine.accounts()?.next().unwrap()?;
+        assert_eq!(acc.total(), amount("6.0"));
+        assert_eq!(acc.held(), amount("5.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_minimal() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,2,1,5.25\ndeposit,1,2,1.0\ndispute,2,1\ndeposit,2,3,0.5",
+        )?;
+        let mut engine = mem_engine();
+        for txn in txns {
+            engine.execute_transaction(txn?)?;
+        }
+        let mut output = vec![];
+        CsvWriterStdout::write_minimal(engine.accounts()?, &mut output)?;
+        let data = String::from_utf8(output)?;
+        assert_eq!(
+            data,
+            "client,available,held\n1,1.0000,0.0000\n2,0.5000,5.2500\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_file() -> Fallible<()> {
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(
+            b"type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,3.0\nwithdrawal,1,3,1.5",
+        )?;
+        let output_file = NamedTempFile::new()?;
+        let report = PaymentsEngine::process_file(
+            input_file.path().to_str().unwrap(),
+            output_file.path().to_str(),
+            &CsvWriterStdout,
+        )?;
+        assert_eq!(report.stats.deposited, amount("8.0"));
+        assert_eq!(report.stats.withdrawn, amount("1.5"));
+        assert!(report.skipped.is_empty());
+        assert_eq!(report.withdrawals_rejected, 0);
+        assert_eq!(
+            std::fs::read_to_string(output_file.path())?,
+            "client,available,held,total,locked\n1,3.5000,0.0000,3.5000,false\n2,3.0000,0.0000,3.0000,false\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reader_from_bytes() -> Fallible<()> {
+        let input: &[u8] = b"type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,1.5";
+        let txns = CsvDataReader::from_reader(input).collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(txns.len(), 2);
+        assert_eq!(txns[1].type_, TransactionType::Withdrawal);
+        assert_eq!(txns[1].amount, Some(amount("1.5")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_many() -> Fallible<()> {
+        fn check(accounts: &mut dyn Store<ClientId, Account>) -> Fallible<()> {
+            let mut acc = Account::new(4);
+            acc.deposit(amount("5.0"))?;
+            accounts.update(acc)?;
+            accounts.update(Account::new(2))?;
+            let found = accounts.get_many(&[4, 9, 2, 4])?;
+            assert_eq!(
+                found,
+                vec![Some(acc), None, Some(Account::new(2)), Some(acc)]
+            );
+            assert_eq!(accounts.get_many(&[])?, vec![]);
+            Ok(())
+        }
+
+        check(&mut AccountsMemStore::new())?;
+        let mut txns = TransactionsMemStore::new();
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 7, Some(amount("1.0")));
+        txns.create(deposit)?;
+        assert_eq!(txns.get_many(&[8, 7])?, vec![None, Some(deposit)]);
+
+        #[cfg(feature = "sqlite")]
+        {
+            use malipo::SqliteAccountsStore;
+            let db = NamedTempFile::new()?;
+            let mut accounts = SqliteAccountsStore::open(db.path().to_str().unwrap())?;
+            check(&mut accounts)?;
+            let ids: Vec<ClientId> = (0..1200).collect();
+            for id in (0..1200).step_by(3) {
+                accounts.update(Account::new(id))?;
+            }
+            let found = accounts.get_many(&ids)?;
+            assert!(found
+                .iter()
+                .zip(&ids)
+                .all(|(acc, id)| acc.is_some() == (id % 3 == 0 || [2, 4].contains(id))));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_stores_persist() -> Fallible<()> {
+        use malipo::{SqliteAccountsStore, SqliteTransactionsStore};
+
+        let db = NamedTempFile::new()?;
+        let path = db.path().to_str().unwrap();
+        let open = || -> Fallible<PaymentsEngine> {
+            Ok(PaymentsEngine::new(
+                Box::new(SqliteAccountsStore::open(path)?),
+                Box::new(SqliteTransactionsStore::open(path)?),
+            ))
+        };
+
+        let mut input = String::from("type,client,tx,amount\n");
+        for id in 1..=1000 {
+            input.push_str(&format!("deposit,{},{},1.5\n", id % 700, id));
+        }
+        input.push_str("withdrawal,1,1001,0.5\ndispute,2,2");
+        let mut engine = open()?;
+        for txn in reader(&input)? {
+            engine.execute_transaction(txn?)?;
+        }
+        drop(engine);
+
+        let mut engine = open()?;
+        let resolve = Transaction::new(TransactionType::Resolve, 2, 2, None);
+        assert_eq!(
+            engine.execute_transaction(resolve)?,
+            TransactionOutcome::Applied
+        );
+        let accounts: Vec<_> = engine.accounts()?.collect::<Fallible<_>>()?;
+        assert_eq!(accounts.len(), 700);
+        let total: Amount = accounts.iter().map(|acc| acc.total()).sum();
+        assert_eq!(total, amount("1499.5"));
+        assert_eq!(accounts[1].available(), amount("2.5"));
+        assert_eq!(accounts[2].held(), Amount::ZERO);
+        assert_eq!(accounts[2].available(), amount("3.0"));
+        assert_eq!(engine.client_ids()?, (0..700).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_idempotency_keys_persist() -> Fallible<()> {
+        use malipo::{SqliteAccountsStore, SqliteTransactionsStore};
+
+        let db = NamedTempFile::new()?;
+        let path = db.path().to_str().unwrap();
+        let open = || -> Fallible<PaymentsEngine> {
+            Ok(PaymentsEngine::new(
+                Box::new(SqliteAccountsStore::open(path)?),
+                Box::new(SqliteTransactionsStore::open(path)?),
+            ))
+        };
+        let txns = reader(
+            "type,client,tx,amount,idempotency_key\ndeposit,1,1,5.0,pay_abc\ndeposit,1,2,5.0,pay_abc\ndeposit,1,3,1.0,pay_def",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let mut engine = open()?;
+        engine.execute_transaction(txns[0])?;
+        drop(engine);
+
+        let mut engine = open()?;
+        assert_eq!(
+            engine.get_transaction(1)?.unwrap().idempotency_key,
+            txns[0].idempotency_key
+        );
+        assert_eq!(
+            engine.execute_batch(&txns[1..])?,
+            vec![
+                TransactionOutcome::rejected(RejectReason::DuplicateIdempotencyKey),
+                TransactionOutcome::Applied,
+            ]
+        );
+        assert_eq!(engine.account(1)?.unwrap().total(), amount("6.0"));
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_read_error() -> Fallible<()> {
+        use malipo::SqliteTransactionsStore;
+
+        let db = NamedTempFile::new()?;
+        let path = db.path().to_str().unwrap();
+        let mut store = SqliteTransactionsStore::open(path)?;
+        for id in 1..=600 {
+            store.create(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                id,
+                Some(amount("1.0")),
+            ))?;
+        }
+        assert_eq!(store.iter()?.count(), 600);
+        // A row on the second page that cannot be read back
+        rusqlite::Connection::open(path)?
+            .execute("UPDATE transactions SET state = 99 WHERE id = 550", [])?;
+        // The first page streams out, then the failed page ends the stream
+        let items: Vec<_> = store.iter()?.collect();
+        assert_eq!(items.len(), 513);
+        assert!(items[..512].iter().all(Result::is_ok));
+        assert!(matches!(items[512], Err(MalipoError::SqliteError(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_spilling_transactions_store() -> Fallible<()> {
+        let segment = NamedTempFile::new()?;
+        let path = segment.path().to_str().unwrap();
+        let mut store = SpillingTransactionsStore::new(path, 2)?;
+        for id in 1..=5 {
+            store.create(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                id,
+                Some(amount("1.5")),
+            ))?;
+        }
+        assert_eq!(store.spilled(), 3);
+        let mut txn = store.get(1)?;
+        assert_eq!(txn.amount, Some(amount("1.5")));
+        txn.dispute()?;
+        store.update(txn)?;
+        assert_eq!(store.get(1)?.state(), TxnState::Disputed);
+        assert_eq!(store.iter()?.count(), 5);
+
+        // Deleting frees the slot on disk and the place in the window
+        store.delete(2)?;
+        store.delete(5)?;
+        store.create(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            6,
+            Some(amount("2.0")),
+        ))?;
+        store.create(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            7,
+            Some(amount("3.0")),
+        ))?;
+        assert_eq!((store.spilled(), store.iter()?.count()), (3, 5));
+        assert_eq!(segment.as_file().metadata()?.len(), 3 * 21);
+        let mut ids = store.ids()?.collect::<Fallible<Vec<_>>>()?;
+        ids.sort_unstable();
+        assert_eq!(ids, [1, 3, 4, 6, 7]);
+        assert_eq!(store.get(4)?.amount, Some(amount("1.5")));
+        assert_eq!(store.get(6)?.amount, Some(amount("2.0")));
+
+        // A corrupt spilled record is yielded as an error, the rest still read
+        let mut file = segment.reopen()?;
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&[u8::MAX])?;
+        let (read, failed): (Vec<_>, Vec<_>) = store.iter()?.partition(Result::is_ok);
+        assert_eq!(read.len(), 4);
+        assert!(matches!(failed[..], [Err(MalipoError::IoError(_))]));
+
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,1.0\ndeposit,1,3,1.0\ndeposit,1,4,1.0\ndispute,1,1",
+        )?;
+        let txn_store = Box::new(SpillingTransactionsStore::new(path, 2)?);
+        let mut engine = PaymentsEngine::new(Box::new(AccountsMemStore::new()), txn_store);
+        let mut outcomes = vec![];
+        for txn in txns {
+            outcomes.push(engine.execute_transaction(txn?)?);
+        }
+        assert_eq!(outcomes[4], TransactionOutcome::Applied);
+        assert_eq!(engine.accounts()?.next().unwrap()?.held(), amount("10.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_backends_agree() -> Fallible<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\nwithdrawal,1,3,2.5\ndispute,1,1\nresolve,1,1\ndispute,2,2\nchargeback,2,2\nauthorize,1,4,1.0\ncapture,1,4\nwithdrawal,1,5,100.0\ndispute,1,3";
+        let run = |mut engine: PaymentsEngine| -> Fallible<PaymentsEngine> {
+            for txn in reader(input)? {
+                engine.execute_transaction(txn?)?;
+            }
+            Ok(engine)
+        };
+        let mem = run(mem_engine())?;
+
+        let segment = NamedTempFile::new()?;
+        let txn_store = SpillingTransactionsStore::new(segment.path().to_str().unwrap(), 1)?;
+        let spilling = run(PaymentsEngine::new(
+            Box::new(AccountsMemStore::new()),
+            Box::new(txn_store),
+        ))?;
+        assert_engines_equal(&mem, &spilling)?;
+
+        #[cfg(feature = "sqlite")]
+        {
+            use malipo::{SqliteAccountsStore, SqliteTransactionsStore};
+            let db = NamedTempFile::new()?;
+            let path = db.path().to_str().unwrap();
+            let sqlite = run(PaymentsEngine::new(
+                Box::new(SqliteAccountsStore::open(path)?),
+                Box::new(SqliteTransactionsStore::open(path)?),
+            ))?;
+            assert_engines_equal(&mem, &sqlite)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_operation_counts() -> Fallible<()> {
+        let acc_store = InstrumentedStore::new(AccountsMemStore::new());
+        let txn_store = InstrumentedStore::new(TransactionsMemStore::new());
+        let (acc_counter, txn_counter) = (acc_store.counter(), txn_store.counter());
+        let mut engine = PaymentsEngine::new(Box::new(acc_store), Box::new(txn_store));
+        for txn in reader("type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1")? {
+            engine.execute_transaction(txn?)?;
+        }
+        assert_eq!(
+            acc_counter.counts(),
+            StoreCounts {
+                get: 2,
+                find: 2,
+                update: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            txn_counter.counts(),
+            StoreCounts {
+                create: 1,
+                get: 3,
+                update: 1,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_runner() -> Fallible<()> {
+        let txns =
+            reader("type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,9.0\ndispute,1,1")?;
+        let mut runner = StepRunner::new(mem_engine(), txns);
+
+        let (txn, outcome) = runner.step()?.unwrap();
+        assert_eq!((txn.type_, txn.id), (TransactionType::Deposit, 1));
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        assert_eq!(
+            runner.engine().accounts()?.next().unwrap()?.total(),
+            amount("5.0")
+        );
+
+        let (txn, outcome) = runner.step()?.unwrap();
+        assert_eq!((txn.type_, txn.id), (TransactionType::Withdrawal, 2));
+        assert_eq!(
+            outcome,
+            TransactionOutcome::rejected(RejectReason::InsufficientFunds)
+        );
+
+        let (txn, outcome) = runner.step()?.unwrap();
+        assert_eq!((txn.type_, txn.id), (TransactionType::Dispute, 1));
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        assert_eq!(
+            runner.engine().accounts()?.next().unwrap()?.held(),
+            amount("5.0")
+        );
+
+        assert!(runner.step()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_terminator() -> Fallible<()> {
+        let mut engine = mem_engine();
+        for txn in reader("type,client,tx,amount\ndeposit,1,1,5.0")? {
+            engine.execute_transaction(txn?)?;
+        }
+
+        let mut output = vec![];
+        CsvWriterStdout::write_with_config(
+            engine.accounts()?,
+            &mut output,
+            &CsvWriterConfig::default(),
+        )?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n"
+        );
+
+        let mut output = vec![];
+        let config = CsvWriterConfig {
+            terminator: LineTerminator::Crlf,
+            ..Default::default()
+        };
+        CsvWriterStdout::write_with_config(engine.accounts()?, &mut output, &config)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "client,available,held,total,locked\r\n1,5.0000,0.0000,5.0000,false\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_emit_bom() -> Fallible<()> {
+        let mut engine = mem_engine();
+        for txn in reader("type,client,tx,amount\ndeposit,1,1,5.0")? {
+            engine.execute_transaction(txn?)?;
+        }
+        let expected = "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n";
+
+        let mut output = vec![];
+        let config = CsvWriterConfig {
+            emit_bom: true,
+            ..Default::default()
+        };
+        CsvWriterStdout::write_with_config(engine.accounts()?, &mut output, &config)?;
+        assert_eq!(output[..3], [0xef, 0xbb, 0xbf]);
+        assert_eq!(String::from_utf8(output[3..].to_vec())?, expected);
+
+        let mut output = vec![];
+        CsvWriterStdout::write_with_config(
+            engine.accounts()?,
+            &mut output,
+            &CsvWriterConfig::default(),
+        )?;
+        assert_eq!(String::from_utf8(output)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_audit() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,1,2,2.5\ndispute,1,1\nresolve,1,1\nresolve,1,1",
+        )?;
+        let mut engine = mem_engine();
+        for txn in txns {
+            engine.execute_transaction(txn?)?;
+        }
+        let mut output = vec![];
+        engine.write_dispute_audit(&mut output)?;
+        let data = String::from_utf8(output)?;
+        assert_eq!(
+            data,
+            "tx,client,action,amount,held_after\n1,1,dispute,5.0000,5.0000\n1,1,resolve,5.0000,0.0000\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback_on_transaction_store_failure() -> Fallible<()> {
+        /// Transactions store that cannot persist new transactions
+        struct FailingTransactions(TransactionsMemStore);
+        impl Store<TransactionId, Transaction> for FailingTransactions {
+            fn create(&mut self, _item: Transaction) -> Fallible<()> {
+                Err(std::io::Error::other("store unavailable").into())
+            }
+            fn delete(&mut self, id: TransactionId) -> Fallible<()> {
+                self.0.delete(id)
+            }
+            fn get(&mut self, id: TransactionId) -> Fallible<Transaction> {
+                self.0.get(id)
+            }
+            fn update(&mut self, item: Transaction) -> Fallible<()> {
+                self.0.update(item)
+            }
+            fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Transaction>> + '_>> {
+                self.0.iter()
+            }
+        }
+        let acc_store = Box::new(AccountsMemStore::new());
+        let txn_store = Box::new(FailingTransactions(TransactionsMemStore::new()));
+        let mut engine = PaymentsEngine::new(acc_store, txn_store);
+        for txn in reader("type,client,tx,amount\ndeposit,1,1,5.0")? {
+            assert!(matches!(
+                engine.execute_transaction(txn?),
+                Err(MalipoError::IoError(_))
+            ));
+        }
+        assert_eq!(engine.account(1)?, None);
+        assert_eq!(engine.stats().deposited, Amount::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prevalidate_disputes() -> Fallible<()> {
+        let txns = reader(
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,2\ndeposit,1,2,1.0\ndispute,1,1\nresolve,2,1\nchargeback,1,9",
+        )?
+        .collect::<Fallible<Vec<_>>>()?;
+        let issues = PaymentsEngine::prevalidate_disputes(txns);
+        assert_eq!(issues.len(), 3);
+        assert!(matches!(
+            issues[0],
+            (TransactionType::Dispute, 2, InvalidReference::Later)
+        ));
+        assert!(matches!(
+            issues[1],
+            (TransactionType::Resolve, 1, InvalidReference::WrongClient)
+        ));
+        assert!(matches!(
+            issues[2],
+            (TransactionType::Chargeback, 9, InvalidReference::Missing)
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_skip_event() -> Fallible<()> {
+        use std::sync::{Arc, Mutex};
+
+        struct Capture(Arc<Mutex<Vec<u8>>>);
+        impl Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let logs = Arc::new(Mutex::new(vec![]));
+        let sink = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || Capture(sink.clone()))
+            .finish();
+        tracing::subscriber::with_default(subscriber, || -> Fallible<()> {
+            let txns = reader("type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,7")?;
+            let mut engine = mem_engine();
+            for txn in txns {
+                engine.execute_transaction(txn?)?;
+            }
+            Ok(())
+        })?;
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone())?;
+        assert!(logs.contains("execute_transaction{tx=7 client=1 type_=Dispute}"));
+        assert!(logs.contains("transaction not found, dispute skipped"));
+        Ok(())
+    }
 }
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use rusqlite::types::Type;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row, ToSql};
+
+use crate::store::{encode, TXN_STATES, TXN_TYPES};
+use crate::{
+    Account, Amount, ClientId, Fallible, IdempotencyKey, MalipoError, Store, Transaction,
+    TransactionId,
+};
+
+/// Rows fetched per query when iterating over a table
+const PAGE_SIZE: i64 = 512;
+
+/// Value for a stored code
+fn decode<T: Copy>(codes: &[T], row: &Row, idx: usize) -> rusqlite::Result<T> {
+    let code: i64 = row.get(idx)?;
+    usize::try_from(code)
+        .ok()
+        .and_then(|code| codes.get(code))
+        .copied()
+        .ok_or(rusqlite::Error::IntegralValueOutOfRange(idx, code))
+}
+
+/// Rows of a keyset-paginated query, read a page at a time as the
+/// iterator advances. The query takes the last key seen and the page size
+/// as parameters. A page that fails to load is yielded as an error, after
+/// which the iterator ends.
+struct Paged<'a, T> {
+    conn: &'a Connection,
+    sql: &'static str,
+    map: fn(&Row) -> rusqlite::Result<T>,
+    key: fn(&T) -> i64,
+    page: std::vec::IntoIter<T>,
+    after: i64,
+    done: bool,
+}
+
+impl<T> Paged<'_, T> {
+    /// Read the page after the last key seen
+    fn next_page(&mut self) -> Fallible<Vec<T>> {
+        let mut stmt = self.conn.prepare_cached(self.sql)?;
+        let page = stmt
+            .query_map(params![self.after, PAGE_SIZE], self.map)?
+            .collect::<rusqlite::Result<Vec<T>>>()?;
+        Ok(page)
+    }
+}
+
+impl<T> Iterator for Paged<'_, T> {
+    type Item = Fallible<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.page.next() {
+                return Some(Ok(item));
+            }
+            if self.done {
+                return None;
+            }
+            match self.next_page() {
+                Ok(page) => {
+                    self.done = (page.len() as i64) < PAGE_SIZE;
+                    if let Some(last) = page.last() {
+                        self.after = (self.key)(last);
+                    }
+                    self.page = page.into_iter();
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// Stream the rows of a keyset-paginated query. The statement is prepared
+/// up front so a malformed query fails before iteration starts.
+fn paged<'a, T: 'a>(
+    conn: &'a Connection,
+    sql: &'static str,
+    map: fn(&Row) -> rusqlite::Result<T>,
+    key: fn(&T) -> i64,
+) -> Fallible<Box<dyn Iterator<Item = Fallible<T>> + 'a>> {
+    conn.prepare_cached(sql)?;
+    Ok(Box::new(Paged {
+        conn,
+        sql,
+        map,
+        key,
+        page: Vec::new().into_iter(),
+        after: -1,
+        done: false,
+    }))
+}
+
+/// Look up rows by key, `PAGE_SIZE` keys per query, and return them in the
+/// order of `ids`. `select` is completed with an `IN` list of the keys.
+fn by_keys<K: Copy + Eq + Hash + ToSql, T: Copy>(
+    conn: &Connection,
+    select: &str,
+    ids: &[K],
+    map: fn(&Row) -> rusqlite::Result<T>,
+    key: fn(&T) -> K,
+) -> Fallible<Vec<Option<T>>> {
+    let mut found = HashMap::new();
+    for chunk in ids.chunks(PAGE_SIZE as usize) {
+        let sql = format!("{} IN ({})", select, vec!["?"; chunk.len()].join(", "));
+        let mut stmt = conn.prepare(&sql)?;
+        for item in stmt.query_map(params_from_iter(chunk), map)? {
+            let item = item?;
+            found.insert(key(&item), item);
+        }
+    }
+    Ok(ids.iter().map(|id| found.get(id).copied()).collect())
+}
+
+/// SQLite store for accounts
+pub struct SqliteAccountsStore(Connection);
+
+impl SqliteAccountsStore {
+    /// Open, creating if needed, the accounts table in the database at `path`
+    pub fn open(path: &str) -> Fallible<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS accounts (
+                client_id INTEGER PRIMARY KEY,
+                available INTEGER NOT NULL,
+                held INTEGER NOT NULL,
+                total INTEGER NOT NULL,
+                locked INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        Ok(Self(conn))
+    }
+
+    fn account(row: &Row) -> rusqlite::Result<Account> {
+        Ok(Account::from_parts(
+            row.get(0)?,
+            Amount::from_raw(row.get(1)?),
+            Amount::from_raw(row.get(2)?),
+            Amount::from_raw(row.get(3)?),
+            row.get(4)?,
+        ))
+    }
+}
+
+impl Store<ClientId, Account> for SqliteAccountsStore {
+    fn create(&mut self, item: Account) -> Fallible<()> {
+        self.update(item)
+    }
+    fn get(&mut self, id: ClientId) -> Fallible<Account> {
+        match self.find(id)? {
+            Some(acc) => Ok(acc),
+            None => {
+                let acc = Account::new(id);
+                self.create(acc)?;
+                Ok(acc)
+            }
+        }
+    }
+    fn find(&self, id: ClientId) -> Fallible<Option<Account>> {
+        Ok(self
+            .0
+            .query_row(
+                "SELECT client_id, available, held, total, locked FROM accounts
+                 WHERE client_id = ?1",
+                [id],
+                Self::account,
+            )
+            .optional()?)
+    }
+    fn get_many(&self, ids: &[ClientId]) -> Fallible<Vec<Option<Account>>> {
+        by_keys(
+            &self.0,
+            "SELECT client_id, available, held, total, locked FROM accounts WHERE client_id",
+            ids,
+            Self::account,
+            |acc| acc.client_id,
+        )
+    }
+    fn delete(&mut self, id: ClientId) -> Fallible<()> {
+        self.0
+            .execute("DELETE FROM accounts WHERE client_id = ?1", [id])?;
+        Ok(())
+    }
+    fn update(&mut self, item: Account) -> Fallible<()> {
+        self.0.execute(
+            "INSERT OR REPLACE INTO accounts (client_id, available, held, total, locked)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                item.client_id,
+                item.available().raw(),
+                item.held().raw(),
+                item.total().raw(),
+                item.is_frozen()
+            ],
+        )?;
+        Ok(())
+    }
+    fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Account>> + '_>> {
+        paged(
+            &self.0,
+            "SELECT client_id, available, held, total, locked FROM accounts
+             WHERE client_id > ?1 ORDER BY client_id LIMIT ?2",
+            Self::account,
+            |acc| acc.client_id.into(),
+        )
+    }
+    fn ids(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<ClientId>> + '_>> {
+        paged(
+            &self.0,
+            "SELECT client_id FROM accounts
+             WHERE client_id > ?1 ORDER BY client_id LIMIT ?2",
+            |row| row.get(0),
+            |id| (*id).into(),
+        )
+    }
+}
+
+/// SQLite store for transactions
+pub struct SqliteTransactionsStore(Connection);
+
+impl SqliteTransactionsStore {
+    /// Open, creating if needed, the transactions table in the database at `path`
+    pub fn open(path: &str) -> Fallible<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS transactions (
+                id INTEGER PRIMARY KEY,
+                type INTEGER NOT NULL,
+                client_id INTEGER NOT NULL,
+                amount INTEGER,
+                state INTEGER NOT NULL,
+                idempotency_key TEXT
+            )",
+            [],
+        )?;
+        Ok(Self(conn))
+    }
+
+    fn transaction(row: &Row) -> rusqlite::Result<Transaction> {
+        let amount: Option<i64> = row.get(3)?;
+        let key: Option<String> = row.get(5)?;
+        let mut txn = Transaction::new(
+            decode(&TXN_TYPES, row, 1)?,
+            row.get(2)?,
+            row.get(0)?,
+            amount.map(Amount::from_raw),
+        );
+        txn.idempotency_key = key
+            .map(|key| key.parse())
+            .transpose()
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(5, Type::Text, Box::new(e)))?;
+        Ok(txn.with_state(decode(&TXN_STATES, row, 4)?))
+    }
+}
+
+impl Store<TransactionId, Transaction> for SqliteTransactionsStore {
+    fn create(&mut self, txn: Transaction) -> Fallible<()> {
+        self.update(txn)
+    }
+    fn delete(&mut self, id: TransactionId) -> Fallible<()> {
+        self.0
+            .execute("DELETE FROM transactions WHERE id = ?1", [id])?;
+        Ok(())
+    }
+    fn get(&mut self, id: TransactionId) -> Fallible<Transaction> {
+        self.find(id)?.ok_or(MalipoError::TransactionNotFound(id))
+    }
+    fn find(&self, id: TransactionId) -> Fallible<Option<Transaction>> {
+        Ok(self
+            .0
+            .query_row(
+                "SELECT id, type, client_id, amount, state, idempotency_key FROM transactions WHERE id = ?1",
+                [id],
+                Self::transaction,
+            )
+            .optional()?)
+    }
+    fn get_many(&self, ids: &[TransactionId]) -> Fallible<Vec<Option<Transaction>>> {
+        by_keys(
+            &self.0,
+            "SELECT id, type, client_id, amount, state, idempotency_key FROM transactions WHERE id",
+            ids,
+            Self::transaction,
+            |txn| txn.id,
+        )
+    }
+    fn update(&mut self, txn: Transaction) -> Fallible<()> {
+        self.0.execute(
+            "INSERT OR REPLACE INTO transactions
+             (id, type, client_id, amount, state, idempotency_key)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                txn.id,
+                i64::from(encode(&TXN_TYPES, &txn.type_)),
+                txn.client_id,
+                txn.amount.map(Amount::raw),
+                i64::from(encode(&TXN_STATES, &txn.state())),
+                txn.idempotency_key.as_ref().map(IdempotencyKey::as_str)
+            ],
+        )?;
+        Ok(())
+    }
+    fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Transaction>> + '_>> {
+        paged(
+            &self.0,
+            "SELECT id, type, client_id, amount, state, idempotency_key FROM transactions
+             WHERE id > ?1 ORDER BY id LIMIT ?2",
+            Self::transaction,
+            |txn| txn.id.into(),
+        )
+    }
+    fn ids(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<TransactionId>> + '_>> {
+        paged(
+            &self.0,
+            "SELECT id FROM transactions WHERE id > ?1 ORDER BY id LIMIT ?2",
+            |row| row.get(0),
+            |id| (*id).into(),
+        )
+    }
+}
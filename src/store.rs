@@ -1,6 +1,17 @@
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
 
-use crate::{Account, ClientId, Fallible, MalipoError, Store, Transaction, TransactionId};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Account, Amount, AuditEvent, AuditLog, ClientId, Fallible, IdempotencyKey, Keyed, MalipoError,
+    MinimalAccount, ParseAmountError, RejectReason, Store, Transaction, TransactionId,
+    TransactionType, TxnState,
+};
 
 /// In-memory store for accounts
 #[derive(Debug, Clone, Default)]
@@ -24,6 +35,9 @@ impl Store<ClientId, Account> for AccountsMemStore {
             .or_insert_with(|| Account::new(id))
             .to_owned())
     }
+    fn find(&self, id: ClientId) -> Fallible<Option<Account>> {
+        Ok(self.0.get(&id).copied())
+    }
     fn delete(&mut self, id: ClientId) -> Fallible<()> {
         self.0.remove(&id);
         Ok(())
@@ -32,10 +46,13 @@ impl Store<ClientId, Account> for AccountsMemStore {
         self.0.insert(item.client_id, item);
         Ok(())
     }
-    fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Account> + '_>> {
-        let iter = self.0.values().copied();
+    fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Account>> + '_>> {
+        let iter = self.0.values().copied().map(Ok);
         Ok(Box::new(iter))
     }
+    fn ids(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<ClientId>> + '_>> {
+        Ok(Box::new(self.0.keys().copied().map(Ok)))
+    }
 }
 
 /// In-memory store for Transactions
@@ -66,42 +83,673 @@ impl Store<TransactionId, Transaction> for TransactionsMemStore {
             .ok_or(MalipoError::TransactionNotFound(id))
     }
 
+    fn find(&self, id: TransactionId) -> Fallible<Option<Transaction>> {
+        Ok(self.0.get(&id).copied())
+    }
+
     fn update(&mut self, txn: Transaction) -> Fallible<()> {
         self.0.insert(txn.id, txn);
         Ok(())
     }
 
-    fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Transaction> + '_>> {
-        let iter = self.0.values().copied();
+    fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Transaction>> + '_>> {
+        let iter = self.0.values().copied().map(Ok);
         Ok(Box::new(iter))
     }
+
+    fn ids(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<TransactionId>> + '_>> {
+        Ok(Box::new(self.0.keys().copied().map(Ok)))
+    }
+}
+
+/// Transaction types in the order of their stored codes
+pub(crate) const TXN_TYPES: [TransactionType; 8] = [
+    TransactionType::Authorize,
+    TransactionType::Capture,
+    TransactionType::Chargeback,
+    TransactionType::Deposit,
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::Void,
+    TransactionType::Withdrawal,
+];
+
+/// Transaction states in the order of their stored codes
+pub(crate) const TXN_STATES: [TxnState; 8] = [
+    TxnState::Normal,
+    TxnState::Disputed,
+    TxnState::Resolved,
+    TxnState::ChargedBack,
+    TxnState::Authorized,
+    TxnState::Captured,
+    TxnState::Voided,
+    TxnState::Reversed,
+];
+
+/// Stored code of a value, its position in `codes`
+pub(crate) fn encode<T: PartialEq>(codes: &[T], value: &T) -> u8 {
+    codes.iter().position(|code| code == value).unwrap() as u8
+}
+
+/// Size of a transaction in a spill segment: id, type, client, amount
+/// presence, amount, state and idempotency key index (zero without a key).
+/// Segments only live as long as the store, so the index into the
+/// process's key table stands in for the key's text.
+const SPILLED_LEN: usize = 4 + 1 + 2 + 1 + 8 + 1 + 4;
+
+/// Transactions store keeping the most recent transactions in memory and
+/// spilling older ones to a segment file, from which `get` reads them back
+pub struct SpillingTransactionsStore {
+    recent: HashMap<TransactionId, Transaction>,
+    order: VecDeque<TransactionId>,
+    window: usize,
+    segment: File,
+    spilled: HashMap<TransactionId, u64>,
+    free: Vec<u64>,
+    segment_len: u64,
+}
+
+impl SpillingTransactionsStore {
+    /// Create a store holding at most `window` transactions in memory and
+    /// spilling to a segment file at `path`, which is truncated
+    pub fn new(path: &str, window: usize) -> Fallible<Self> {
+        let segment = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            recent: HashMap::new(),
+            order: VecDeque::new(),
+            window,
+            segment,
+            spilled: HashMap::new(),
+            free: Vec::new(),
+            segment_len: 0,
+        })
+    }
+
+    /// Number of transactions currently spilled to disk
+    pub fn spilled(&self) -> usize {
+        self.spilled.len()
+    }
+
+    fn write_at(&mut self, offset: u64, txn: &Transaction) -> Fallible<()> {
+        let mut buf = [0; SPILLED_LEN];
+        buf[0..4].copy_from_slice(&txn.id.to_le_bytes());
+        buf[4] = encode(&TXN_TYPES, &txn.type_);
+        buf[5..7].copy_from_slice(&txn.client_id.to_le_bytes());
+        buf[7] = txn.amount.is_some() as u8;
+        let amount = txn.amount.map_or(0, Amount::raw);
+        buf[8..16].copy_from_slice(&amount.to_le_bytes());
+        buf[16] = encode(&TXN_STATES, &txn.state());
+        let key = txn.idempotency_key.map_or(0, |key| key.index());
+        buf[17..21].copy_from_slice(&key.to_le_bytes());
+        self.segment.seek(SeekFrom::Start(offset))?;
+        self.segment.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn read_at(mut segment: &File, offset: u64) -> Fallible<Transaction> {
+        let mut buf = [0; SPILLED_LEN];
+        segment.seek(SeekFrom::Start(offset))?;
+        segment.read_exact(&mut buf)?;
+        let corrupt = || std::io::Error::new(std::io::ErrorKind::InvalidData, "corrupt segment");
+        let type_ = *TXN_TYPES.get(usize::from(buf[4])).ok_or_else(corrupt)?;
+        let state = *TXN_STATES.get(usize::from(buf[16])).ok_or_else(corrupt)?;
+        let amount = i64::from_le_bytes(buf[8..16].try_into().unwrap());
+        let key = match u32::from_le_bytes(buf[17..21].try_into().unwrap()) {
+            0 => None,
+            index => Some(IdempotencyKey::from_index(index).ok_or_else(corrupt)?),
+        };
+        let mut txn = Transaction::new(
+            type_,
+            ClientId::from_le_bytes([buf[5], buf[6]]),
+            TransactionId::from_le_bytes(buf[0..4].try_into().unwrap()),
+            (buf[7] == 1).then(|| Amount::from_raw(amount)),
+        );
+        txn.idempotency_key = key;
+        Ok(txn.with_state(state))
+    }
+
+    /// Move the oldest in-memory transactions to the segment file until
+    /// the window is respected, reusing the slots of deleted transactions
+    fn spill(&mut self) -> Fallible<()> {
+        while self.recent.len() > self.window {
+            let Some(id) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(txn) = self.recent.remove(&id) {
+                let offset = match self.free.pop() {
+                    Some(offset) => offset,
+                    None => {
+                        self.segment_len += SPILLED_LEN as u64;
+                        self.segment_len - SPILLED_LEN as u64
+                    }
+                };
+                self.write_at(offset, &txn)?;
+                self.spilled.insert(id, offset);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Store<TransactionId, Transaction> for SpillingTransactionsStore {
+    fn create(&mut self, txn: Transaction) -> Fallible<()> {
+        self.update(txn)
+    }
+
+    fn delete(&mut self, id: TransactionId) -> Fallible<()> {
+        if self.recent.remove(&id).is_some() {
+            self.order.retain(|&o| o != id);
+        }
+        if let Some(offset) = self.spilled.remove(&id) {
+            self.free.push(offset);
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, id: TransactionId) -> Fallible<Transaction> {
+        self.find(id)?.ok_or(MalipoError::TransactionNotFound(id))
+    }
+
+    fn find(&self, id: TransactionId) -> Fallible<Option<Transaction>> {
+        if let Some(txn) = self.recent.get(&id) {
+            return Ok(Some(*txn));
+        }
+        match self.spilled.get(&id) {
+            Some(offset) => Self::read_at(&self.segment, *offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn update(&mut self, txn: Transaction) -> Fallible<()> {
+        if let Some(offset) = self.spilled.get(&txn.id).copied() {
+            return self.write_at(offset, &txn);
+        }
+        if self.recent.insert(txn.id, txn).is_none() {
+            self.order.push_back(txn.id);
+        }
+        self.spill()
+    }
+
+    /// Spilled transactions are read back one at a time as the iterator
+    /// advances, yielding an error for a record that cannot be read
+    fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Transaction>> + '_>> {
+        let recent = self.recent.values().copied().map(Ok);
+        let spilled = self
+            .spilled
+            .values()
+            .map(|offset| Self::read_at(&self.segment, *offset));
+        Ok(Box::new(recent.chain(spilled)))
+    }
+
+    /// IDs come from the index, without reading back spilled transactions
+    fn ids(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<TransactionId>> + '_>> {
+        let ids = self
+            .recent
+            .keys()
+            .chain(self.spilled.keys())
+            .copied()
+            .map(Ok);
+        Ok(Box::new(ids))
+    }
+}
+
+/// Number of calls made to each store operation
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StoreCounts {
+    /// Calls to `create`
+    pub create: usize,
+    /// Calls to `delete`
+    pub delete: usize,
+    /// Calls to `get`
+    pub get: usize,
+    /// Calls to `find`
+    pub find: usize,
+    /// Calls to `get_many`
+    pub get_many: usize,
+    /// Calls to `update`
+    pub update: usize,
+    /// Calls to `iter`
+    pub iter: usize,
+    /// Calls to `ids`
+    pub ids: usize,
+}
+
+/// Handle for reading the counts of an instrumented store
+#[derive(Debug, Default, Clone)]
+pub struct StoreCounter(Rc<Cell<StoreCounts>>);
+
+impl StoreCounter {
+    /// Operation counts so far
+    pub fn counts(&self) -> StoreCounts {
+        self.0.get()
+    }
+
+    fn record(&self, op: impl FnOnce(&mut StoreCounts)) {
+        let mut counts = self.0.get();
+        op(&mut counts);
+        self.0.set(counts);
+    }
+}
+
+/// Store decorator counting the operations made on the wrapped store
+pub struct InstrumentedStore<S> {
+    inner: S,
+    counter: StoreCounter,
+}
+
+impl<S> InstrumentedStore<S> {
+    /// Wrap a store
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            counter: StoreCounter::default(),
+        }
+    }
+
+    /// Operation counts so far
+    pub fn counts(&self) -> StoreCounts {
+        self.counter.counts()
+    }
+
+    /// Handle for reading the counts once the store is handed to an engine
+    pub fn counter(&self) -> StoreCounter {
+        self.counter.clone()
+    }
+}
+
+impl<Id, Item, S: Store<Id, Item>> Store<Id, Item> for InstrumentedStore<S> {
+    fn create(&mut self, item: Item) -> Fallible<()> {
+        self.counter.record(|counts| counts.create += 1);
+        self.inner.create(item)
+    }
+    fn delete(&mut self, id: Id) -> Fallible<()> {
+        self.counter.record(|counts| counts.delete += 1);
+        self.inner.delete(id)
+    }
+    fn get(&mut self, id: Id) -> Fallible<Item> {
+        self.counter.record(|counts| counts.get += 1);
+        self.inner.get(id)
+    }
+    fn find(&self, id: Id) -> Fallible<Option<Item>>
+    where
+        Item: Keyed<Id>,
+        Id: PartialEq,
+    {
+        self.counter.record(|counts| counts.find += 1);
+        self.inner.find(id)
+    }
+    fn get_many(&self, ids: &[Id]) -> Fallible<Vec<Option<Item>>>
+    where
+        Item: Keyed<Id>,
+        Id: Copy + PartialEq,
+    {
+        self.counter.record(|counts| counts.get_many += 1);
+        self.inner.get_many(ids)
+    }
+    fn update(&mut self, item: Item) -> Fallible<()> {
+        self.counter.record(|counts| counts.update += 1);
+        self.inner.update(item)
+    }
+    fn iter(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Item>> + '_>> {
+        self.counter.record(|counts| counts.iter += 1);
+        self.inner.iter()
+    }
+    fn ids(&self) -> Fallible<Box<dyn Iterator<Item = Fallible<Id>> + '_>>
+    where
+        Item: Keyed<Id> + 'static,
+    {
+        self.counter.record(|counts| counts.ids += 1);
+        self.inner.ids()
+    }
+}
+
+/// Transaction row as read from CSV, before the amount is validated
+#[derive(Debug, Deserialize)]
+struct TransactionRecord {
+    #[serde(rename = "type")]
+    type_: TransactionType,
+    #[serde(rename = "client")]
+    client_id: u64,
+    #[serde(rename = "tx")]
+    id: TransactionId,
+    #[serde(default)]
+    amount: Option<String>,
+    #[serde(default)]
+    idempotency_key: Option<String>,
+}
+
+impl TransactionRecord {
+    /// Validate the raw client ID, amount and key and convert the record into
+    /// a transaction. The amount of a type that carries none is dropped unread.
+    fn into_transaction(
+        self,
+        line: u64,
+        parser: &dyn AmountParser,
+        allow_scientific: bool,
+    ) -> Fallible<Transaction> {
+        let value = match self.type_.carries_amount() {
+            true => self.amount.unwrap_or_default(),
+            false => String::new(),
+        };
+        let amount = match value.is_empty() {
+            false => Some(parse_amount(&value, line, parser, allow_scientific)?),
+            true => None,
+        };
+        let client_id =
+            ClientId::try_from(self.client_id).map_err(|_| MalipoError::ClientIdOutOfRange {
+                value: self.client_id.to_string(),
+                max: ClientId::MAX.into(),
+            })?;
+        let mut txn = Transaction::new(self.type_, client_id, self.id, amount);
+        txn.idempotency_key = match self.idempotency_key.filter(|key| !key.is_empty()) {
+            Some(key) => Some(
+                key.parse()
+                    .map_err(|_| MalipoError::InvalidIdempotencyKey { line, value: key })?,
+            ),
+            None => None,
+        };
+        // A zero deposit is left for the engine, which accepts it only when
+        // configured to treat it as an account activation
+        txn.validate_activation()
+            .map_err(|_| MalipoError::InvalidAmount { line, value })?;
+        Ok(txn)
+    }
 }
 
-/// CSV Data Reader
-pub struct CsvDataReader(csv::Reader<std::fs::File>);
+/// Transaction row whose type may be missing, read by header names
+#[derive(Debug, Deserialize)]
+struct SignedRecord {
+    #[serde(rename = "type", default, deserialize_with = "empty_as_none")]
+    type_: Option<TransactionType>,
+    #[serde(rename = "client")]
+    client_id: u64,
+    #[serde(rename = "tx")]
+    id: TransactionId,
+    #[serde(default)]
+    amount: Option<String>,
+    #[serde(default)]
+    idempotency_key: Option<String>,
+}
+
+impl SignedRecord {
+    /// Fill in a missing type from the sign of the amount
+    fn into_record(self, line: u64) -> Fallible<TransactionRecord> {
+        let (type_, amount) = match (self.type_, self.amount) {
+            (Some(type_), amount) => (type_, amount),
+            (None, Some(value)) => match value.strip_prefix('-') {
+                Some(abs) => (TransactionType::Withdrawal, Some(abs.to_owned())),
+                None => (TransactionType::Deposit, Some(value)),
+            },
+            (None, None) => {
+                return Err(MalipoError::InvalidAmount {
+                    line,
+                    value: String::new(),
+                })
+            }
+        };
+        Ok(TransactionRecord {
+            type_,
+            client_id: self.client_id,
+            id: self.id,
+            amount,
+            idempotency_key: self.idempotency_key,
+        })
+    }
+}
+
+/// Deserialize an empty type cell as no type
+fn empty_as_none<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<TransactionType>, D::Error> {
+    use serde::de::IntoDeserializer;
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) if !value.is_empty() => {
+            TransactionType::deserialize(value.into_deserializer()).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Parses the amount column of a transaction row
+pub trait AmountParser {
+    /// Parse an amount from its raw CSV field
+    fn parse(&self, value: &str) -> Result<Amount, ParseAmountError>;
+}
+
+/// Parser for plain four decimal place amounts
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultAmountParser;
+
+impl AmountParser for DefaultAmountParser {
+    fn parse(&self, value: &str) -> Result<Amount, ParseAmountError> {
+        value.parse()
+    }
+}
+
+/// Parse an amount, expanding scientific notation first if allowed, and
+/// report any failure as invalid at `line`
+fn parse_amount(
+    value: &str,
+    line: u64,
+    parser: &dyn AmountParser,
+    allow_scientific: bool,
+) -> Fallible<Amount> {
+    let expanded = match allow_scientific {
+        true => expand_scientific(value),
+        false => None,
+    };
+    parser
+        .parse(expanded.as_deref().unwrap_or(value))
+        .map_err(|_| MalipoError::InvalidAmount {
+            line,
+            value: value.to_owned(),
+        })
+}
+
+/// Rewrite an amount in scientific notation as a plain decimal. The
+/// mantissa needs at least one digit, so `e3` or `.e2` are left as they are.
+fn expand_scientific(value: &str) -> Option<String> {
+    let (mantissa, exp) = value.split_once(['e', 'E'])?;
+    let exp: i32 = exp
+        .parse()
+        .ok()
+        .filter(|exp: &i32| exp.unsigned_abs() <= 32)?;
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", mantissa.strip_prefix('+').unwrap_or(mantissa)),
+    };
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = format!("{}{}", int, frac);
+    if digits.is_empty() {
+        return None;
+    }
+    let point = int.len() as i32 + exp;
+    let plain = if point <= 0 {
+        format!("0.{}{}", "0".repeat(-point as usize), digits)
+    } else if point as usize >= digits.len() {
+        format!("{}{}", digits, "0".repeat(point as usize - digits.len()))
+    } else {
+        let (int, frac) = digits.split_at(point as usize);
+        format!("{}.{}", int, frac)
+    };
+    Some(format!("{}{}", sign, plain))
+}
+
+/// Columns a row may leave out under `strict_columns`, when they end the
+/// header
+const OPTIONAL_COLUMNS: [&str; 2] = ["amount", "idempotency_key"];
+
+/// Configuration for reading transactions
+#[derive(Debug, Clone, Default)]
+pub struct CsvReaderConfig {
+    /// Accept amounts in scientific notation, such as `1e3`
+    pub allow_scientific: bool,
+    /// Read columns by their header names and, for rows without a type,
+    /// treat a negative amount as a withdrawal of its absolute value and
+    /// any other amount as a deposit
+    pub infer_type_from_sign: bool,
+    /// Accept transaction types in any case, such as `Deposit` or `DEPOSIT`
+    pub case_insensitive_types: bool,
+    /// Fail rows with more or fewer fields than the header. A row may still
+    /// leave out the trailing amount and idempotency key columns, as
+    /// disputes, resolves and chargebacks do.
+    pub strict_columns: bool,
+}
+
+/// CSV Data Reader over a file, stdin or any other source
+pub struct CsvDataReader<R = std::fs::File> {
+    rdr: csv::Reader<R>,
+    config: CsvReaderConfig,
+    headers: Option<csv::StringRecord>,
+    parser: Box<dyn AmountParser>,
+}
 
 impl CsvDataReader {
     /// Create new reader from a path
     pub fn new(fname: &str) -> Fallible<CsvDataReader> {
-        let rdr = csv::ReaderBuilder::new()
-            .flexible(true)
-            .trim(csv::Trim::All)
+        Self::with_config(fname, CsvReaderConfig::default())
+    }
+
+    /// Create new reader from a path with the given configuration
+    pub fn with_config(fname: &str, config: CsvReaderConfig) -> Fallible<CsvDataReader> {
+        let rdr = Self::builder()
             .from_path(fname)
             .map_err(MalipoError::CsvError)?;
-        Ok(CsvDataReader(rdr))
+        Ok(CsvDataReader {
+            rdr,
+            parser: Box::new(DefaultAmountParser),
+            config,
+            headers: None,
+        })
+    }
+}
+
+impl<R: std::io::Read> CsvDataReader<R> {
+    /// Create new reader over any source of CSV data
+    pub fn from_reader(rdr: R) -> CsvDataReader<R> {
+        Self::from_reader_with_config(rdr, CsvReaderConfig::default())
+    }
+
+    /// Create new reader over any source of CSV data with the given configuration
+    pub fn from_reader_with_config(rdr: R, config: CsvReaderConfig) -> CsvDataReader<R> {
+        CsvDataReader {
+            rdr: Self::builder().from_reader(rdr),
+            parser: Box::new(DefaultAmountParser),
+            config,
+            headers: None,
+        }
+    }
+
+    /// Parse amounts with `parser` instead of the default parser. With
+    /// `allow_scientific`, amounts in scientific notation are expanded to
+    /// plain decimals before `parser` sees them.
+    pub fn with_amount_parser(mut self, parser: impl AmountParser + 'static) -> Self {
+        self.parser = Box::new(parser);
+        self
+    }
+
+    fn builder() -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        builder.flexible(true).trim(csv::Trim::All);
+        builder
     }
 }
 
-impl Iterator for CsvDataReader {
+impl<R: std::io::Read> Iterator for CsvDataReader<R> {
     type Item = Fallible<Transaction>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().map(|(_, txn)| txn)
+    }
+}
+
+impl<R: std::io::Read> CsvDataReader<R> {
+    /// Yield each raw record alongside the transaction parsed from it
+    pub fn with_raw(self) -> RawRecords<R> {
+        RawRecords(self)
+    }
+
+    /// Copy of the record with the type column in lower case
+    fn lowercase_type(&self, rec: &csv::StringRecord) -> csv::StringRecord {
+        let type_idx = self
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.iter().position(|name| name == "type"))
+            .unwrap_or(0);
+        rec.iter()
+            .enumerate()
+            .map(|(idx, field)| match idx == type_idx {
+                true => field.to_lowercase(),
+                false => field.to_owned(),
+            })
+            .collect()
+    }
+
+    /// Check the record has as many fields as the header, or only lacks
+    /// trailing optional columns
+    fn check_columns(&mut self, rec: &csv::StringRecord, line: u64) -> Fallible<()> {
+        let headers = self.rdr.headers().map_err(MalipoError::CsvError)?;
+        let expected = headers.len();
+        let omits_optional = headers
+            .iter()
+            .skip(rec.len())
+            .all(|name| OPTIONAL_COLUMNS.contains(&name));
+        match rec.len() <= expected && omits_optional {
+            true => Ok(()),
+            false => Err(MalipoError::ColumnCount {
+                line,
+                expected,
+                found: rec.len(),
+            }),
+        }
+    }
+
+    /// Read the next record and parse it. A record that cannot be read is
+    /// returned empty.
+    fn next_record(&mut self) -> Option<(csv::StringRecord, Fallible<Transaction>)> {
         let mut rec = csv::StringRecord::new();
-        match self.0.read_record(&mut rec) {
-            Err(e) => Some(Err(MalipoError::CsvError(e))),
+        if self.config.infer_type_from_sign && self.headers.is_none() {
+            match self.rdr.headers() {
+                Ok(headers) => self.headers = Some(headers.clone()),
+                Err(e) => return Some((rec, Err(MalipoError::CsvError(e)))),
+            }
+        }
+        match self.rdr.read_record(&mut rec) {
+            Err(e) => Some((rec, Err(MalipoError::CsvError(e)))),
             Ok(rec_read) => {
                 if rec_read {
-                    Some(rec.deserialize(None).map_err(MalipoError::CsvError))
+                    let line = rec.position().map_or(0, |pos| pos.line());
+                    if self.config.strict_columns {
+                        if let Err(e) = self.check_columns(&rec, line) {
+                            return Some((rec, Err(e)));
+                        }
+                    }
+                    let record = match self.config.case_insensitive_types {
+                        true => self.lowercase_type(&rec),
+                        false => rec.clone(),
+                    };
+                    let record = match &self.headers {
+                        Some(headers) => record
+                            .deserialize::<SignedRecord>(Some(headers))
+                            .map_err(MalipoError::CsvError)
+                            .and_then(|record| record.into_record(line)),
+                        None => record
+                            .deserialize::<TransactionRecord>(None)
+                            .map_err(MalipoError::CsvError),
+                    };
+                    let txn = record.and_then(|record| {
+                        record.into_transaction(
+                            line,
+                            self.parser.as_ref(),
+                            self.config.allow_scientific,
+                        )
+                    });
+                    Some((rec, txn))
                 } else {
                     None
                 }
@@ -110,54 +758,405 @@ impl Iterator for CsvDataReader {
     }
 }
 
+/// Stream of raw CSV records paired with the transactions parsed from them
+pub struct RawRecords<R>(CsvDataReader<R>);
+
+impl<R: std::io::Read> Iterator for RawRecords<R> {
+    type Item = (csv::StringRecord, Fallible<Transaction>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_record()
+    }
+}
+
+/// Adapters over a stream of transactions
+pub trait TransactionStreamExt: Iterator<Item = Fallible<Transaction>> + Sized {
+    /// Yield only the first occurrence of each transaction type and ID
+    fn dedup_by_id(self) -> DedupById<Self> {
+        DedupById {
+            inner: self,
+            seen: HashSet::new(),
+        }
+    }
+}
+
+impl<I: Iterator<Item = Fallible<Transaction>>> TransactionStreamExt for I {}
+
+/// Stream of transactions with duplicate rows removed
+pub struct DedupById<I> {
+    inner: I,
+    seen: HashSet<(TransactionType, TransactionId)>,
+}
+
+impl<I: Iterator<Item = Fallible<Transaction>>> Iterator for DedupById<I> {
+    type Item = Fallible<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for item in self.inner.by_ref() {
+            match item {
+                Ok(txn) if !self.seen.insert((txn.type_, txn.id)) => continue,
+                item => return Some(item),
+            }
+        }
+        None
+    }
+}
+
+/// Line terminator for CSV output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineTerminator {
+    /// `\n`
+    #[default]
+    Lf,
+    /// `\r\n`
+    Crlf,
+}
+
+/// Column names of the account output
+const ACCOUNT_HEADER: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+/// CSV output configuration
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CsvWriterConfig {
+    /// Line terminator written after each record
+    pub terminator: LineTerminator,
+    /// Start the output with a UTF-8 byte order mark, for Excel
+    pub emit_bom: bool,
+}
+
+impl CsvWriterConfig {
+    fn writer<W: std::io::Write>(&self, wtr: W) -> csv::Writer<W> {
+        let terminator = match self.terminator {
+            LineTerminator::Lf => csv::Terminator::Any(b'\n'),
+            LineTerminator::Crlf => csv::Terminator::CRLF,
+        };
+        csv::WriterBuilder::new()
+            .terminator(terminator)
+            .has_headers(false)
+            .from_writer(wtr)
+    }
+}
+
+/// Output format for accounts
+pub trait AccountWriter {
+    /// Write accounts to `wtr`, ordered by client ID
+    fn write(
+        &self,
+        accounts: Box<dyn Iterator<Item = Fallible<Account>> + '_>,
+        wtr: &mut dyn std::io::Write,
+    ) -> Fallible<()>;
+}
+
+/// Accounts ordered by client ID, so output is deterministic. Fails on the
+/// first account that could not be read.
+fn sorted<T, K: Ord>(
+    accounts: impl Iterator<Item = Fallible<T>>,
+    key: fn(&T) -> K,
+) -> Fallible<Vec<T>> {
+    let mut accounts = accounts.collect::<Fallible<Vec<_>>>()?;
+    accounts.sort_by_key(key);
+    Ok(accounts)
+}
+
 /// CSV Data to Stdout Writer
 pub struct CsvWriterStdout;
 
 impl CsvWriterStdout {
-    #[cfg(not(debug_assertions))]
-    /// Write accounts to stdout
+    /// Write accounts to `wtr`, or stdout when `None`
     pub fn write<W: std::io::Write>(
-        accounts: Box<dyn Iterator<Item = Account> + '_>,
+        accounts: Box<dyn Iterator<Item = Fallible<Account>> + '_>,
         wtr: Option<W>,
     ) -> Fallible<()> {
-        if let Some(w) = wtr {
-            let mut writer = csv::Writer::from_writer(w);
-            for acc in accounts {
-                writer.serialize(acc).map_err(MalipoError::CsvError)?;
+        let config = CsvWriterConfig::default();
+        match wtr {
+            Some(w) => Self::write_with_config(accounts, w, &config),
+            None => Self::write_with_config(accounts, std::io::stdout(), &config),
+        }
+    }
+
+    /// Write accounts using the given output configuration. The header is
+    /// written even when there are no accounts.
+    pub fn write_with_config<W: std::io::Write>(
+        accounts: Box<dyn Iterator<Item = Fallible<Account>> + '_>,
+        wtr: W,
+        config: &CsvWriterConfig,
+    ) -> Fallible<()> {
+        let mut wtr = wtr;
+        if config.emit_bom {
+            wtr.write_all("\u{feff}".as_bytes())?;
+        }
+        let mut writer = config.writer(wtr);
+        writer
+            .write_record(ACCOUNT_HEADER)
+            .map_err(MalipoError::CsvError)?;
+        for acc in sorted(accounts, |acc| acc.client_id)? {
+            writer.serialize(acc).map_err(MalipoError::CsvError)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write only the client, available and held columns of accounts,
+    /// ordered by client ID
+    pub fn write_minimal<W: std::io::Write>(
+        accounts: Box<dyn Iterator<Item = Fallible<Account>> + '_>,
+        wtr: W,
+    ) -> Fallible<()> {
+        let mut writer = csv::Writer::from_writer(wtr);
+        for acc in sorted(accounts.map(|acc| acc.map(MinimalAccount::from)), |acc| {
+            acc.client_id
+        })? {
+            writer.serialize(acc).map_err(MalipoError::CsvError)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl AccountWriter for CsvWriterStdout {
+    fn write(
+        &self,
+        accounts: Box<dyn Iterator<Item = Fallible<Account>> + '_>,
+        wtr: &mut dyn std::io::Write,
+    ) -> Fallible<()> {
+        Self::write(accounts, Some(wtr))
+    }
+}
+
+/// Writes accounts as a JSON array of objects with the same fields and
+/// four decimal place amounts as the CSV output
+pub struct JsonWriter;
+
+impl AccountWriter for JsonWriter {
+    fn write(
+        &self,
+        accounts: Box<dyn Iterator<Item = Fallible<Account>> + '_>,
+        wtr: &mut dyn std::io::Write,
+    ) -> Fallible<()> {
+        let accounts = sorted(accounts, |acc| acc.client_id)?;
+        serde_json::to_writer(&mut *wtr, &accounts).map_err(std::io::Error::from)?;
+        writeln!(wtr)?;
+        Ok(())
+    }
+}
+
+/// Writes accounts as a plain text table with right-aligned columns and a
+/// rule under the header, sized to the widest value of each column
+pub struct TableWriter;
+
+impl AccountWriter for TableWriter {
+    fn write(
+        &self,
+        accounts: Box<dyn Iterator<Item = Fallible<Account>> + '_>,
+        wtr: &mut dyn std::io::Write,
+    ) -> Fallible<()> {
+        let header = ACCOUNT_HEADER.map(String::from);
+        let rows: Vec<[String; 5]> = sorted(accounts, |acc| acc.client_id)?
+            .into_iter()
+            .map(|acc| {
+                [
+                    acc.client_id.to_string(),
+                    acc.available().to_string(),
+                    acc.held().to_string(),
+                    acc.total().to_string(),
+                    acc.is_frozen().to_string(),
+                ]
+            })
+            .collect();
+        let mut widths = header.clone().map(|name| name.len());
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
             }
-            writer.flush()?;
-        } else {
-            let mut writer = csv::Writer::from_writer(std::io::stdout());
-            for acc in accounts {
-                writer.serialize(acc).map_err(MalipoError::CsvError)?;
+        }
+        let rule = widths.map(|width| "-".repeat(width));
+        for row in [&header, &rule].into_iter().chain(&rows) {
+            let cells: Vec<_> = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:>width$}", cell, width = width))
+                .collect();
+            writeln!(wtr, "{}", cells.join("  "))?;
+        }
+        Ok(())
+    }
+}
+
+/// When a `RotatingWriter` rolls over to its next file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    /// After this many account rows
+    Rows(usize),
+    /// Once a file holds at least this many bytes
+    Bytes(u64),
+}
+
+/// Writes accounts as CSV to a numbered series of files, starting the next
+/// file, with its own header, whenever the current one is full. For a path
+/// of `accounts.csv` the files are `accounts.000.csv`, `accounts.001.csv`
+/// and so on.
+pub struct RotatingWriter {
+    path: PathBuf,
+    rotation: Rotation,
+    current: Option<csv::Writer<File>>,
+    rows: usize,
+    files: Vec<PathBuf>,
+}
+
+impl RotatingWriter {
+    /// Rotate files named after `path`. No file is created before the first
+    /// account is written.
+    pub fn new(path: &str, rotation: Rotation) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            rotation,
+            current: None,
+            rows: 0,
+            files: Vec::new(),
+        }
+    }
+
+    /// Append an account, rolling over to a new file afterwards if the
+    /// current one is full
+    pub fn write(&mut self, acc: &Account) -> Fallible<()> {
+        let mut wtr = match self.current.take() {
+            Some(wtr) => wtr,
+            None => self.open_next()?,
+        };
+        wtr.serialize(acc).map_err(MalipoError::CsvError)?;
+        self.rows += 1;
+        let full = match self.rotation {
+            Rotation::Rows(max) => self.rows >= max,
+            Rotation::Bytes(max) => {
+                wtr.flush()?;
+                let path = self.files.last().expect("a file is open");
+                std::fs::metadata(path)?.len() >= max
             }
-            writer.flush()?;
         };
+        match full {
+            true => wtr.flush()?,
+            false => self.current = Some(wtr),
+        }
+        Ok(())
+    }
 
+    /// Flush the rows written to the current file
+    pub fn flush(&mut self) -> Fallible<()> {
+        if let Some(wtr) = self.current.as_mut() {
+            wtr.flush()?;
+        }
         Ok(())
     }
 
-    #[cfg(debug_assertions)]
-    /// Write accounts to stdout
-    pub fn write<W: std::io::Write>(
-        accounts: Box<dyn Iterator<Item = Account> + '_>,
-        wtr: Option<W>,
-    ) -> Fallible<()> {
-        let mut accounts: Vec<_> = accounts.collect();
-        accounts.sort_by_key(|acc| acc.client_id);
-        if let Some(w) = wtr {
-            let mut writer = csv::Writer::from_writer(w);
-            for acc in accounts {
-                writer.serialize(acc).map_err(MalipoError::CsvError)?;
-            }
-            writer.flush()?;
-        } else {
-            let mut writer = csv::Writer::from_writer(std::io::stdout());
-            for acc in accounts {
-                writer.serialize(acc).map_err(MalipoError::CsvError)?;
+    /// Files written so far, oldest first
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    fn open_next(&mut self) -> Fallible<csv::Writer<File>> {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match self.path.extension() {
+            Some(ext) => format!("{}.{:03}.{}", stem, self.files.len(), ext.to_string_lossy()),
+            None => format!("{}.{:03}", stem, self.files.len()),
+        };
+        let path = self.path.with_file_name(name);
+        let wtr = csv::Writer::from_path(&path).map_err(MalipoError::CsvError)?;
+        self.files.push(path);
+        self.rows = 0;
+        Ok(wtr)
+    }
+}
+
+/// Audit log writing one JSON object per event and line
+pub struct JsonLinesAuditLog<W>(W);
+
+impl<W: std::io::Write> JsonLinesAuditLog<W> {
+    /// Log to `wtr`
+    pub fn new(wtr: W) -> Self {
+        Self(wtr)
+    }
+}
+
+impl<W: std::io::Write> AuditLog for JsonLinesAuditLog<W> {
+    fn on_event(&mut self, event: &AuditEvent) -> Fallible<()> {
+        serde_json::to_writer(&mut self.0, event).map_err(std::io::Error::from)?;
+        writeln!(self.0)?;
+        Ok(())
+    }
+}
+
+/// One line of the event log
+#[derive(Serialize)]
+struct EventRecord {
+    tx: TransactionId,
+    client: ClientId,
+    #[serde(rename = "type")]
+    type_: TransactionType,
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<RejectReason>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    balances: Option<EventBalances>,
+}
+
+/// Account balances after an applied transaction
+#[derive(Serialize)]
+struct EventBalances {
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+/// Event log writing one flat JSON object per processed transaction and
+/// line, with its outcome and, when applied or reversed, the resulting
+/// balances
+pub struct JsonLinesEventLog<W>(W);
+
+impl<W: std::io::Write> JsonLinesEventLog<W> {
+    /// Log to `wtr`
+    pub fn new(wtr: W) -> Self {
+        Self(wtr)
+    }
+}
+
+impl<W: std::io::Write> AuditLog for JsonLinesEventLog<W> {
+    fn on_event(&mut self, event: &AuditEvent) -> Fallible<()> {
+        let record = match *event {
+            AuditEvent::Applied { txn, after, .. } | AuditEvent::Reversed { txn, after, .. } => {
+                EventRecord {
+                    tx: txn.id,
+                    client: txn.client_id,
+                    type_: txn.type_,
+                    outcome: match event {
+                        AuditEvent::Reversed { .. } => "reversed",
+                        _ => "applied",
+                    },
+                    reason: None,
+                    balances: Some(EventBalances {
+                        available: after.available(),
+                        held: after.held(),
+                        total: after.total(),
+                        locked: after.is_frozen(),
+                    }),
+                }
             }
-            writer.flush()?;
+            AuditEvent::SkippedFrozen { txn }
+            | AuditEvent::SkippedInsufficientFunds { txn }
+            | AuditEvent::SkippedUnknownTxn { txn }
+            | AuditEvent::SkippedClientMismatch { txn }
+            | AuditEvent::SkippedDuplicate { txn }
+            | AuditEvent::Skipped { txn, .. } => EventRecord {
+                tx: txn.id,
+                client: txn.client_id,
+                type_: txn.type_,
+                outcome: "skipped",
+                reason: event.reason(),
+                balances: None,
+            },
         };
+        serde_json::to_writer(&mut self.0, &record).map_err(std::io::Error::from)?;
+        writeln!(self.0)?;
         Ok(())
     }
 }